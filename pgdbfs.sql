@@ -1,23 +1,43 @@
 \set mntpt /tmp/my_storage
+\set uid 1000
+\set gid 1000
 
+drop table if exists pgdbfs_xattr;
 drop table if exists pgdbfs_data;
+drop table if exists pgdbfs_dentry;
 drop table if exists pgdbfs;
 
 create table pgdbfs (
        id bigint not null,
        mnt_pt varchar(256) not null,
        ino bigint not null,
-       parentid bigint not null,
-       name varchar(256) not null,
        size bigint not null,
        segment_len int not null,
-       is_dir boolean not null default false,
-       create_ts timestamptz default current_timestamp,
-       update_ts timestamptz default current_timestamp,
+       kind char(1) not null default 'f',
+       mode int not null default 420,
+       uid bigint not null default 0,
+       gid bigint not null default 0,
+       atime bigint not null default (extract(epoch from clock_timestamp()) * 1000000000)::bigint,
+       mtime bigint not null default (extract(epoch from clock_timestamp()) * 1000000000)::bigint,
+       ctime bigint not null default (extract(epoch from clock_timestamp()) * 1000000000)::bigint,
+       crtime bigint not null default (extract(epoch from clock_timestamp()) * 1000000000)::bigint,
+       link_target bytea,
+       constraint pgdbfs_kind_ck check (kind in ('d', 'f', 'l')),
        constraint pgdbfs_pk primary key(id),
        constraint pgdbfs_uk unique(mnt_pt, ino)
 );
 
+create table pgdbfs_dentry (
+       id bigint not null primary key,
+       mnt_pt varchar(256) not null,
+       parentid bigint not null,
+       name bytea not null,
+       fsid bigint not null,
+       constraint pgdbfs_dentry_fk foreign key(fsid) references pgdbfs(id) on delete cascade,
+       constraint pgdbfs_dentry_name_ck check (octet_length(name) between 1 and 255),
+       constraint pgdbfs_dentry_uk unique (mnt_pt, parentid, name)
+);
+
 create table pgdbfs_data (
        id bigint not null primary key,
        fsid bigint not null,
@@ -27,23 +47,35 @@ create table pgdbfs_data (
        constraint pgdbfs_data_uk unique (fsid, segment_no)
 );
 
+create table pgdbfs_xattr (
+       fsid bigint not null,
+       name bytea not null,
+       value bytea not null,
+       constraint pgdbfs_xattr_pk primary key(fsid, name),
+       constraint pgdbfs_xattr_fk foreign key(fsid) references pgdbfs(id) on delete cascade
+);
+
 create index mnt_pt_idx on pgdbfs(mnt_pt);
+create index pgdbfs_dentry_parent_idx on pgdbfs_dentry(mnt_pt, parentid);
+create index pgdbfs_dentry_fsid_idx on pgdbfs_dentry(fsid);
 create index pgdbfs_data_fsid_idx on pgdbfs_data(fsid);
 create index pgdbfs_data_fsid_seg_no_idx on pgdbfs_data(fsid, segment_no);
 
-drop sequence ino_seq;
+drop sequence if exists ino_seq;
 create sequence ino_seq;
 drop sequence if exists fsid_seq;
 create sequence fsid_seq;
 
-insert into pgdbfs (id, mnt_pt, ino, parentid, name, size, segment_len, is_dir)
+-- Root directory has no directory entry
+insert into pgdbfs (id, mnt_pt, ino, size, segment_len, kind, mode, uid, gid)
  values (
    (select nextval('fsid_seq')),
    :'mntpt',
    (select nextval('ino_seq')),
-   0,
-   'rz',
    4096,
    0,
-   true
+   'd',
+   493,
+   :uid,
+   :gid
    );
@@ -0,0 +1,107 @@
+-- Upgrades a database created by the original pgdbfs.sql (names and parent
+-- stored on the inode row, timestamptz create_ts/update_ts, varchar names)
+-- to the current schema in place, keeping all files and their data.
+--
+-- Besides the directory entry table with its unique name per directory
+-- (user-009) it applies the schema of:
+--   - user-006: mode, uid and gid per inode
+--   - user-008: inode kind and symlink targets
+--   - user-010: the pgdbfs_xattr table
+--   - user-014: nanosecond atime, mtime, ctime and crtime
+--   - user-019: names stored as bytes, 1 to 255 long
+--
+-- Names that would break the new constraints, duplicates in one directory
+-- and names that are empty or longer than 255 bytes, are listed and the
+-- upgrade stops without changing anything. Rename them and run it again.
+\set uid 1000
+\set gid 1000
+\set ON_ERROR_STOP on
+
+begin;
+
+do $$
+declare
+       r record;
+       bad int := 0;
+begin
+       for r in select mnt_pt, parentid, name, count(*) as cnt from pgdbfs
+              where parentid <> 0 group by mnt_pt, parentid, name having count(*) > 1
+       loop
+              raise notice 'duplicate name: mnt_pt: %, parentid: %, name: %, count: %',
+                     r.mnt_pt, r.parentid, r.name, r.cnt;
+              bad := bad + 1;
+       end loop;
+       for r in select id, mnt_pt, parentid, name from pgdbfs
+              where parentid <> 0 and octet_length(convert_to(name, 'UTF8')) not between 1 and 255
+       loop
+              raise notice 'name length not 1 to 255 bytes: id: %, mnt_pt: %, parentid: %, name: %',
+                     r.id, r.mnt_pt, r.parentid, r.name;
+              bad := bad + 1;
+       end loop;
+       if bad > 0 then
+              raise exception '% names need renaming before the upgrade', bad;
+       end if;
+end
+$$;
+
+-- Inode attributes
+alter table pgdbfs
+       add column kind char(1) not null default 'f',
+       add column mode int not null default 420,
+       add column uid bigint not null default 0,
+       add column gid bigint not null default 0,
+       add column atime bigint not null default (extract(epoch from clock_timestamp()) * 1000000000)::bigint,
+       add column mtime bigint not null default (extract(epoch from clock_timestamp()) * 1000000000)::bigint,
+       add column ctime bigint not null default (extract(epoch from clock_timestamp()) * 1000000000)::bigint,
+       add column crtime bigint not null default (extract(epoch from clock_timestamp()) * 1000000000)::bigint,
+       add column link_target bytea;
+
+-- Nanosecond timestamps from the old timestamptz columns
+update pgdbfs set
+       kind = case when is_dir then 'd' else 'f' end,
+       mode = case when is_dir then 493 else 420 end,
+       uid = :uid,
+       gid = :gid,
+       atime = coalesce((extract(epoch from update_ts) * 1000000000)::bigint, atime),
+       mtime = coalesce((extract(epoch from update_ts) * 1000000000)::bigint, mtime),
+       ctime = coalesce((extract(epoch from update_ts) * 1000000000)::bigint, ctime),
+       crtime = coalesce((extract(epoch from create_ts) * 1000000000)::bigint, crtime);
+
+alter table pgdbfs add constraint pgdbfs_kind_ck check (kind in ('d', 'f', 'l'));
+
+-- Directory entries move to their own table with byte names, the root
+-- directory (parentid 0) has none
+create table pgdbfs_dentry (
+       id bigint not null primary key,
+       mnt_pt varchar(256) not null,
+       parentid bigint not null,
+       name bytea not null,
+       fsid bigint not null,
+       constraint pgdbfs_dentry_fk foreign key(fsid) references pgdbfs(id) on delete cascade,
+       constraint pgdbfs_dentry_name_ck check (octet_length(name) between 1 and 255),
+       constraint pgdbfs_dentry_uk unique (mnt_pt, parentid, name)
+);
+
+insert into pgdbfs_dentry (id, mnt_pt, parentid, name, fsid)
+       select nextval('fsid_seq'), mnt_pt, parentid, convert_to(name, 'UTF8'), id
+       from pgdbfs where parentid <> 0;
+
+alter table pgdbfs
+       drop column parentid,
+       drop column name,
+       drop column is_dir,
+       drop column create_ts,
+       drop column update_ts;
+
+create table pgdbfs_xattr (
+       fsid bigint not null,
+       name bytea not null,
+       value bytea not null,
+       constraint pgdbfs_xattr_pk primary key(fsid, name),
+       constraint pgdbfs_xattr_fk foreign key(fsid) references pgdbfs(id) on delete cascade
+);
+
+create index pgdbfs_dentry_parent_idx on pgdbfs_dentry(mnt_pt, parentid);
+create index pgdbfs_dentry_fsid_idx on pgdbfs_dentry(fsid);
+
+commit;
@@ -0,0 +1,90 @@
+use super::libc::{c_int, EAGAIN, EEXIST, EIO, ENAMETOOLONG, ENOENT, EOVERFLOW};
+use super::postgres;
+use super::postgres::error::SqlState;
+use super::r2d2;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Failure of a PgDbMgr call, mapped to the errno replied to the kernel
+#[derive(Debug)]
+pub enum PgDbFsError {
+    /// A unique constraint was violated, the entry already exists
+    Exists,
+    /// The entry the call operates on does not exist
+    NotFound,
+    /// A name is longer than NAME_MAX bytes
+    NameTooLong,
+    /// A timestamp is outside the range stored in nanoseconds
+    Overflow,
+    /// No connection could be taken from the pool or it was lost
+    Connection(String),
+    /// The statement was canceled, usually by statement_timeout
+    Timeout(String),
+    /// Any other database error
+    Db(String),
+}
+
+pub type PgDbFsResult<T> = Result<T, PgDbFsError>;
+
+impl PgDbFsError {
+    pub fn errno(&self) -> c_int {
+        match self {
+            PgDbFsError::Exists => EEXIST,
+            PgDbFsError::NotFound => ENOENT,
+            PgDbFsError::NameTooLong => ENAMETOOLONG,
+            PgDbFsError::Overflow => EOVERFLOW,
+            PgDbFsError::Connection(_) => EIO,
+            PgDbFsError::Timeout(_) => EAGAIN,
+            PgDbFsError::Db(_) => EIO,
+        }
+    }
+}
+
+impl fmt::Display for PgDbFsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PgDbFsError::Exists => write!(f, "entry exists"),
+            PgDbFsError::NotFound => write!(f, "entry not found"),
+            PgDbFsError::NameTooLong => write!(f, "name too long"),
+            PgDbFsError::Overflow => write!(f, "timestamp out of range"),
+            PgDbFsError::Connection(msg) => write!(f, "connection error: {}", msg),
+            PgDbFsError::Timeout(msg) => write!(f, "statement canceled: {}", msg),
+            PgDbFsError::Db(msg) => write!(f, "db error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for PgDbFsError {}
+
+impl From<postgres::Error> for PgDbFsError {
+    fn from(err: postgres::Error) -> PgDbFsError {
+        let msg = err.to_string();
+        match err.code() {
+            Some(code) if *code == SqlState::UNIQUE_VIOLATION => PgDbFsError::Exists,
+            Some(code) if *code == SqlState::QUERY_CANCELED => PgDbFsError::Timeout(msg),
+            Some(code) if *code == SqlState::LOCK_NOT_AVAILABLE => PgDbFsError::Timeout(msg),
+            // Class 08 and server shutdowns all mean the connection is gone
+            Some(code) if code.code().starts_with("08") || code.code().starts_with("57P") => {
+                PgDbFsError::Connection(msg)
+            }
+            Some(_) => PgDbFsError::Db(msg),
+            None => {
+                let io_err = error::Error::source(&err)
+                    .is_some_and(|s| s.downcast_ref::<io::Error>().is_some());
+                if err.is_closed() || io_err {
+                    PgDbFsError::Connection(msg)
+                } else {
+                    PgDbFsError::Db(msg)
+                }
+            }
+        }
+    }
+}
+
+impl From<r2d2::Error> for PgDbFsError {
+    fn from(err: r2d2::Error) -> PgDbFsError {
+        PgDbFsError::Connection(err.to_string())
+    }
+}
@@ -1,4 +1,4 @@
-extern crate chrono;
+extern crate libc;
 extern crate postgres;
 extern crate r2d2;
 extern crate r2d2_postgres;
@@ -8,26 +8,84 @@ use self::r2d2::PooledConnection;
 
 use self::r2d2_postgres::{postgres::NoTls, PostgresConnectionManager};
 
-use self::time::Timespec;
-
-use std::time::{Duration, UNIX_EPOCH};
+use self::postgres::{Client, Error, Row};
 
-use self::chrono::{DateTime, Utc};
+use self::time::Timespec;
 
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::vec::Vec;
 
 use fsys::PgDbFsConfig;
 
+use self::libc::{XATTR_CREATE, XATTR_REPLACE};
+
+mod error;
+
+pub use self::error::{PgDbFsError, PgDbFsResult};
+
+/// Kind of a pgdbfs entry, stored as a single character in the kind column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntKind {
+    Directory,
+    File,
+    Symlink,
+}
+
+impl EntKind {
+    pub fn from_code(code: &str) -> EntKind {
+        match code {
+            "d" => EntKind::Directory,
+            "l" => EntKind::Symlink,
+            _ => EntKind::File,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            EntKind::Directory => "d",
+            EntKind::File => "f",
+            EntKind::Symlink => "l",
+        }
+    }
+}
+
+/// An inode joined with the directory entry it was reached through. Entries
+/// looked up by inode number have no name and a dentry_id of 0. Names and
+/// link targets are raw bytes and need not be UTF-8.
+#[derive(Debug, Clone)]
 pub struct Ent {
     pub id: i64,
-    pub name: String,
-    pub is_dir: bool,
+    pub dentry_id: i64,
+    pub name: OsString,
+    pub kind: EntKind,
     pub ino: i64,
     pub size: i64,
     pub segment_len: i32,
-    pub create_ts: DateTime<Utc>,
-    pub update_ts: DateTime<Utc>,
+    pub mode: i32,
+    pub uid: i64,
+    pub gid: i64,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+    pub crtime: Timespec,
     pub nlink: i64,
+    pub link_target: Option<OsString>,
+}
+
+impl Ent {
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntKind::Directory
+    }
+}
+
+/// Space and inode usage of a mount point
+pub struct FsUsage {
+    pub used_bytes: i64,
+    pub used_inodes: i64,
+    pub db_size: i64,
 }
 
 pub struct EntData {
@@ -35,295 +93,723 @@ pub struct EntData {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug)]
+/// Longest name in bytes a directory entry can have
+pub const NAME_MAX: usize = 255;
+
+/// Rejects names that do not fit a directory entry
+fn check_name(name: &OsStr) -> PgDbFsResult<()> {
+    if name.as_bytes().len() > NAME_MAX {
+        return Err(PgDbFsError::NameTooLong);
+    }
+    Ok(())
+}
+
+/// Timestamps are stored as nanoseconds since the epoch, which covers the
+/// years 1677 to 2262
+pub fn timespec_to_ns(ts: &Timespec) -> PgDbFsResult<i64> {
+    ts.sec
+        .checked_mul(1_000_000_000)
+        .and_then(|ns| ns.checked_add(ts.nsec as i64))
+        .ok_or(PgDbFsError::Overflow)
+}
+
+pub fn ns_to_timespec(ns: i64) -> Timespec {
+    Timespec::new(
+        ns.div_euclid(1_000_000_000),
+        ns.rem_euclid(1_000_000_000) as i32,
+    )
+}
+
+/// Attribute changes applied by setattr, None leaves the value unchanged.
+/// The change time is always set.
+pub struct AttrChanges {
+    pub mode: Option<i32>,
+    pub uid: Option<i64>,
+    pub gid: Option<i64>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+    pub ctime: Timespec,
+}
+
+/// Owner given to a newly created inode
+pub struct Owner {
+    pub uid: i64,
+    pub gid: i64,
+}
+
+/// Link count of the inode aliased as p: directories count "." and the ".."
+/// of every sub directory, other inodes count their directory entries
+const NLINK_SQL: &str = "(case when p.kind='d' then 2 + (select count(*) from pgdbfs_dentry c join pgdbfs cp on cp.id=c.fsid where c.parentid=p.ino and cp.kind='d')
+        else (select count(*) from pgdbfs_dentry c where c.fsid=p.id) end)::int8 as nlink";
+
+/// Builds an entry from a pgdbfs row, the dentry columns and nlink are
+/// optional in the row
+fn make_ent(row: &Row) -> Ent {
+    Ent {
+        id: row.get("id"),
+        dentry_id: row.try_get("dentry_id").unwrap_or(0),
+        ino: row.get("ino"),
+        name: row
+            .try_get("name")
+            .map(OsString::from_vec)
+            .unwrap_or_default(),
+        kind: EntKind::from_code(row.get("kind")),
+        size: row.get("size"),
+        segment_len: row.get("segment_len"),
+        mode: row.get("mode"),
+        uid: row.get("uid"),
+        gid: row.get("gid"),
+        atime: ns_to_timespec(row.get("atime")),
+        mtime: ns_to_timespec(row.get("mtime")),
+        ctime: ns_to_timespec(row.get("ctime")),
+        crtime: ns_to_timespec(row.get("crtime")),
+        nlink: row.try_get("nlink").unwrap_or(0),
+        link_target: row
+            .try_get("link_target")
+            .unwrap_or(None)
+            .map(OsString::from_vec),
+    }
+}
+
+/// Upserts segments of a file in a single multi-row statement and grows the
+/// file size to cover the last of them
+fn upsert_segments(
+    tx: &mut Client,
+    file_id: &i64,
+    segments: &[(i64, &[u8])],
+    mtime: i64,
+    ctime: i64,
+) -> Result<(), Error> {
+    let sql = "insert into pgdbfs_data (id, fsid, segment_no, data)
+            select nextval('fsid_seq'), $1, s.segment_no, s.data from unnest($2::int8[], $3::bytea[]) as s(segment_no, data)
+            on conflict on constraint pgdbfs_data_uk do update set data=excluded.data";
+    // ctime is the time of the change, mtime may have been set back by utimens
+    let sz_sql = "update pgdbfs set size=greatest(size, $2::int8 * segment_len + $3::int8), mtime=$4, ctime=$5 where id=$1";
+
+    let segment_nos: Vec<i64> = segments.iter().map(|s| s.0).collect();
+    let data: Vec<&[u8]> = segments.iter().map(|s| s.1).collect();
+    tx.execute(sql, &[file_id, &segment_nos, &data])?;
+    let max_end = segments
+        .iter()
+        .map(|s| (s.0, s.1.len() as i64))
+        .max()
+        .unwrap_or((0, 0));
+    tx.execute(sz_sql, &[file_id, &max_end.0, &max_end.1, &mtime, &ctime])?;
+    Ok(())
+}
+
+type PgConnection = PooledConnection<PostgresConnectionManager<NoTls>>;
+
+/// Connection used by a single PgDbMgr call, either the connection held by an
+/// open transaction or one taken from the pool for just this call
+enum DbConn<'a> {
+    Held(&'a mut PgConnection),
+    Pooled(Box<PgConnection>),
+}
+
+impl<'a> Deref for DbConn<'a> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        match self {
+            DbConn::Held(conn) => conn,
+            DbConn::Pooled(conn) => conn,
+        }
+    }
+}
+
+impl<'a> DerefMut for DbConn<'a> {
+    fn deref_mut(&mut self) -> &mut Client {
+        match self {
+            DbConn::Held(conn) => conn,
+            DbConn::Pooled(conn) => conn,
+        }
+    }
+}
+
 pub struct PgDbMgr {
     cfg: PgDbFsConfig,
     pool: Option<r2d2::Pool<PostgresConnectionManager<NoTls>>>,
+    /// Connection of the transaction opened by begin, every call runs on it
+    /// until commit or rollback
+    tx_conn: Option<PgConnection>,
+}
+
+impl fmt::Debug for PgDbMgr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PgDbMgr")
+            .field("cfg", &self.cfg)
+            .field("pool", &self.pool)
+            .field("in_tx", &self.tx_conn.is_some())
+            .finish()
+    }
 }
 
+/// Statements cast every parameter they use in more than one place, e.g.
+/// `$1::varchar`, so Postgres deduces a single type for it
 impl PgDbMgr {
     pub fn new(cfg: PgDbFsConfig) -> PgDbMgr {
         PgDbMgr {
             cfg: cfg,
             pool: None,
+            tx_conn: None,
         }
     }
 
-    pub fn init(&mut self) {
+    pub fn init(&mut self) -> PgDbFsResult<()> {
         let host = format!(
             "host = {} user = {} password = {} dbname = {}",
             self.cfg.db_host, self.cfg.db_user, self.cfg.db_pass, self.cfg.db_user
         );
-        let cm = PostgresConnectionManager::new(host.parse().unwrap(), NoTls);
+        let config = match host.parse() {
+            Ok(config) => config,
+            Err(err) => return Err(PgDbFsError::from(err)),
+        };
+        let cm = PostgresConnectionManager::new(config, NoTls);
         debug!("Connecting to : {}", host);
-        self.pool = Some(r2d2::Pool::builder().max_size(15).build(cm).unwrap());
+        self.pool = Some(r2d2::Pool::builder().max_size(15).build(cm)?);
+        Ok(())
     }
 
-    fn connect(&self) -> PooledConnection<PostgresConnectionManager<NoTls>> {
+    fn pooled(&self) -> PgDbFsResult<PgConnection> {
         match self.pool.as_ref() {
+            None => Err(PgDbFsError::Connection(String::from(
+                "PgDbMgr not initialized, call init first",
+            ))),
+            Some(p) => Ok(p.get()?),
+        }
+    }
+
+    fn connect(&mut self) -> PgDbFsResult<DbConn<'_>> {
+        match self.tx_conn {
+            Some(ref mut conn) => Ok(DbConn::Held(conn)),
+            None => Ok(DbConn::Pooled(Box::new(self.pooled()?))),
+        }
+    }
+
+    /// Starts a transaction, calls made until commit or rollback run on one
+    /// held connection and take effect together. Fails if a transaction is
+    /// already open or could not be started.
+    pub fn begin(&mut self) -> PgDbFsResult<()> {
+        if self.tx_conn.is_some() {
+            return Err(PgDbFsError::Db(String::from(
+                "begin called with a transaction already open",
+            )));
+        }
+        let mut conn = self.pooled()?;
+        conn.batch_execute("begin")?;
+        self.tx_conn = Some(conn);
+        Ok(())
+    }
+
+    /// Commits the open transaction. A statement that failed without the
+    /// caller noticing leaves the transaction aborted, that is detected here
+    /// and the transaction rolled back.
+    pub fn commit(&mut self) -> PgDbFsResult<()> {
+        let mut conn = match self.tx_conn.take() {
             None => {
-                panic!("PgDbMgr not initialized, call init first")
-            }
-            Some(p) => {
-                let conn = p.get().unwrap();
-                return conn;
+                return Err(PgDbFsError::Db(String::from(
+                    "commit called without an open transaction",
+                )))
             }
+            Some(conn) => conn,
         };
+        let result = conn
+            .batch_execute("select 1")
+            .and_then(|_| conn.batch_execute("commit"));
+        if let Err(err) = result {
+            error!("commit failed, rolling back: {:?}", err);
+            if let Err(err) = conn.batch_execute("rollback") {
+                error!("rollback failed: {:?}", err);
+            }
+            return Err(PgDbFsError::from(err));
+        }
+        Ok(())
     }
 
-    pub fn mkdir(&mut self, mnt_pt: &String, parent: i64, name: &str) {
-        let sql = "insert into pgdbfs (id, mnt_pt, ino, parentid, name, size, segment_len, is_dir) values ((select nextval('fsid_seq')), $1, (select nextval('ino_seq')), $2, $3, 4096, 0, true)";
-        let mut conn = self.connect();
-        match conn.execute(sql, &[&mnt_pt, &parent, &name]) {
-            Result::Ok(val) => {
-                debug!("$$$ {:?}", val)
-            }
-            Result::Err(err) => {
-                panic!("mkdir failed: {:?}", err);
+    /// Rolls back the open transaction, if any
+    pub fn rollback(&mut self) {
+        if let Some(mut conn) = self.tx_conn.take() {
+            if let Err(err) = conn.batch_execute("rollback") {
+                error!("rollback failed: {:?}", err);
             }
         }
     }
 
-    pub fn mkfile(&mut self, mnt_pt: &String, parent: i64, name: &str, segment_len: &i32) {
-        let sql = "insert into pgdbfs (id, mnt_pt, ino, parentid, name, size, segment_len, is_dir) values ((select nextval('fsid_seq')), $1, (select nextval('ino_seq')), $2, $3, 0, $4, false)";
-        let mut conn = self.connect();
-        match conn.execute(sql, &[&mnt_pt, &parent, &name, segment_len]) {
+    /// Runs f atomically, in its own transaction or in a savepoint of the open
+    /// transaction
+    fn atomically<T, F>(&mut self, f: F) -> PgDbFsResult<T>
+    where
+        F: FnOnce(&mut Client) -> Result<T, Error>,
+    {
+        let (begin, commit, rollback) = if self.tx_conn.is_some() {
+            (
+                "savepoint pgdbfs_sp",
+                "release savepoint pgdbfs_sp",
+                "rollback to savepoint pgdbfs_sp",
+            )
+        } else {
+            ("begin", "commit", "rollback")
+        };
+        let mut conn = self.connect()?;
+        conn.batch_execute(begin)?;
+        match f(&mut conn) {
             Result::Ok(val) => {
-                debug!("$$$ {:?}", val)
+                conn.batch_execute(commit)?;
+                Ok(val)
             }
             Result::Err(err) => {
-                panic!("mkdir failed: {:?}", err);
+                if let Err(rb_err) = conn.batch_execute(rollback) {
+                    error!("rollback failed: {:?}", rb_err);
+                }
+                Err(PgDbFsError::from(err))
             }
         }
     }
 
-    pub fn setattr(
+    /// Creates a directory, the parent directory modification and change
+    /// times are set to now in the same statement. Fails with Exists if an
+    /// entry with the same name already exists under the parent.
+    pub fn mkdir(
         &mut self,
         mnt_pt: &String,
-        ino: i64,
-        size: i64,
-        create_ts: Timespec,
-        update_ts: Timespec,
-    ) -> u64 {
-        let sql =
-            "update pgdbfs set create_ts=$1, update_ts=$2, size=$3 where mnt_pt=$4 and ino=$5";
-        let mut conn = self.connect();
-
-        let cts: DateTime<Utc> =
-            DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_secs(create_ts.sec as u64));
+        parent: i64,
+        name: &OsStr,
+        mode: i32,
+        owner: &Owner,
+        now: &Timespec,
+    ) -> PgDbFsResult<Ent> {
+        let sql = "with i as (insert into pgdbfs (id, mnt_pt, ino, size, segment_len, kind, mode, uid, gid, atime, mtime, ctime, crtime)
+                select (select nextval('fsid_seq')), $1::varchar, (select nextval('ino_seq')), 4096, 0, 'd', $4::int4, $5::int8, $6::int8, $7::int8, $7::int8, $7::int8, $7::int8
+                where not exists (select 1 from pgdbfs_dentry where mnt_pt=$1::varchar and parentid=$2::int8 and name=$3::bytea)
+                returning *),
+                e as (insert into pgdbfs_dentry (id, mnt_pt, parentid, name, fsid)
+                select (select nextval('fsid_seq')), $1::varchar, $2::int8, $3::bytea, i.id from i returning id, name),
+                t as (update pgdbfs set mtime=$7::int8, ctime=$7::int8 where mnt_pt=$1::varchar and ino=$2::int8 and exists (select 1 from i))
+                select i.*, e.id as dentry_id, e.name, 2::int8 as nlink from i, e";
+        check_name(name)?;
+        let now = timespec_to_ns(now)?;
+        let mut conn = self.connect()?;
+        match conn.query_opt(
+            sql,
+            &[
+                &mnt_pt,
+                &parent,
+                &name.as_bytes(),
+                &mode,
+                &owner.uid,
+                &owner.gid,
+                &now,
+            ],
+        )? {
+            Some(row) => Ok(make_ent(&row)),
+            None => {
+                debug!("mkdir(parent: {}, name: {:?}) exists", parent, name);
+                Err(PgDbFsError::Exists)
+            }
+        }
+    }
 
-        let uts: DateTime<Utc> =
-            DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_secs(update_ts.sec as u64));
+    /// Creates a file entry in a single statement, fails with Exists if an
+    /// entry with the same name already exists under the parent
+    pub fn create_file(
+        &mut self,
+        mnt_pt: &String,
+        parent: i64,
+        name: &OsStr,
+        mode: i32,
+        owner: &Owner,
+        now: &Timespec,
+    ) -> PgDbFsResult<Ent> {
+        let sql = "with i as (insert into pgdbfs (id, mnt_pt, ino, size, segment_len, kind, mode, uid, gid, atime, mtime, ctime, crtime)
+                select (select nextval('fsid_seq')), $1::varchar, (select nextval('ino_seq')), 0, $4::int4, 'f', $5::int4, $6::int8, $7::int8, $8::int8, $8::int8, $8::int8, $8::int8
+                where not exists (select 1 from pgdbfs_dentry where mnt_pt=$1::varchar and parentid=$2::int8 and name=$3::bytea)
+                returning *),
+                e as (insert into pgdbfs_dentry (id, mnt_pt, parentid, name, fsid)
+                select (select nextval('fsid_seq')), $1::varchar, $2::int8, $3::bytea, i.id from i returning id, name),
+                t as (update pgdbfs set mtime=$8::int8, ctime=$8::int8 where mnt_pt=$1::varchar and ino=$2::int8 and exists (select 1 from i))
+                select i.*, e.id as dentry_id, e.name, 1::int8 as nlink from i, e";
+        check_name(name)?;
+        let now = timespec_to_ns(now)?;
+        let segment_len = self.cfg.db_segment_len;
+        let query = |tx: &mut Client| {
+            tx.query_opt(
+                sql,
+                &[
+                    &mnt_pt,
+                    &parent,
+                    &name.as_bytes(),
+                    &segment_len,
+                    &mode,
+                    &owner.uid,
+                    &owner.gid,
+                    &now,
+                ],
+            )
+        };
+        // A duplicate name inserted concurrently fails the unique constraint,
+        // the savepoint keeps an open transaction usable for the caller.
+        // Outside a transaction the statement commits on its own.
+        let row = if self.tx_conn.is_some() {
+            self.atomically(query)?
+        } else {
+            let mut conn = self.connect()?;
+            query(&mut conn)?
+        };
+        match row {
+            Some(row) => Ok(make_ent(&row)),
+            None => {
+                debug!("create_file(parent: {}, name: {:?}) exists", parent, name);
+                Err(PgDbFsError::Exists)
+            }
+        }
+    }
 
-        match conn.execute(sql, &[&cts, &uts, &size, &mnt_pt, &ino]) {
-            Result::Ok(val) => val,
-            Result::Err(err) => {
+    /// Creates a symbolic link pointing at target, fails with Exists if an
+    /// entry with the same name already exists under the parent
+    pub fn create_symlink(
+        &mut self,
+        mnt_pt: &String,
+        parent: i64,
+        name: &OsStr,
+        target: &OsStr,
+        owner: &Owner,
+        now: &Timespec,
+    ) -> PgDbFsResult<Ent> {
+        let sql = "with i as (insert into pgdbfs (id, mnt_pt, ino, size, segment_len, kind, mode, uid, gid, link_target, atime, mtime, ctime, crtime)
+                select (select nextval('fsid_seq')), $1::varchar, (select nextval('ino_seq')), octet_length($4::bytea), 0, 'l', 511, $5::int8, $6::int8, $4::bytea, $7::int8, $7::int8, $7::int8, $7::int8
+                where not exists (select 1 from pgdbfs_dentry where mnt_pt=$1::varchar and parentid=$2::int8 and name=$3::bytea)
+                returning *),
+                e as (insert into pgdbfs_dentry (id, mnt_pt, parentid, name, fsid)
+                select (select nextval('fsid_seq')), $1::varchar, $2::int8, $3::bytea, i.id from i returning id, name),
+                t as (update pgdbfs set mtime=$7::int8, ctime=$7::int8 where mnt_pt=$1::varchar and ino=$2::int8 and exists (select 1 from i))
+                select i.*, e.id as dentry_id, e.name, 1::int8 as nlink from i, e";
+        check_name(name)?;
+        let now = timespec_to_ns(now)?;
+        let mut conn = self.connect()?;
+        match conn.query_opt(
+            sql,
+            &[
+                &mnt_pt,
+                &parent,
+                &name.as_bytes(),
+                &target.as_bytes(),
+                &owner.uid,
+                &owner.gid,
+                &now,
+            ],
+        )? {
+            Some(row) => Ok(make_ent(&row)),
+            None => {
                 debug!(
-                    "Failed to setattr for: {}, {}, reason: {}",
-                    mnt_pt, ino, err
+                    "create_symlink(parent: {}, name: {:?}) exists",
+                    parent, name
                 );
-                0
+                Err(PgDbFsError::Exists)
             }
         }
     }
 
+    /// Adds a directory entry for an existing inode, fails with Exists if the
+    /// inode is a directory or an entry with the same name already exists.
+    /// Touches the change time of the inode and the times of the parent.
+    pub fn link(
+        &mut self,
+        mnt_pt: &String,
+        ino: i64,
+        parent: i64,
+        name: &OsStr,
+        now: &Timespec,
+    ) -> PgDbFsResult<()> {
+        let sql = "with e as (insert into pgdbfs_dentry (id, mnt_pt, parentid, name, fsid)
+                select (select nextval('fsid_seq')), $1::varchar, $2::int8, $3::bytea, p.id from pgdbfs p
+                where p.mnt_pt=$1::varchar and p.ino=$4::int8 and p.kind<>'d'
+                and not exists (select 1 from pgdbfs_dentry where mnt_pt=$1::varchar and parentid=$2::int8 and name=$3::bytea)
+                returning fsid),
+                t as (update pgdbfs set mtime=$5::int8, ctime=$5::int8 where mnt_pt=$1::varchar and ino=$2::int8 and exists (select 1 from e))
+                update pgdbfs p set ctime=$5::int8 from e where p.id=e.fsid";
+        check_name(name)?;
+        let now = timespec_to_ns(now)?;
+        let mut conn = self.connect()?;
+        match conn.execute(sql, &[&mnt_pt, &parent, &name.as_bytes(), &ino, &now])? {
+            1 => Ok(()),
+            _ => Err(PgDbFsError::Exists),
+        }
+    }
+
+    /// Applies chmod, chown and utimens changes, the change time is always set
+    pub fn setattr(
+        &mut self,
+        mnt_pt: &String,
+        ino: i64,
+        changes: &AttrChanges,
+    ) -> PgDbFsResult<u64> {
+        let sql = "update pgdbfs set mode=coalesce($1, mode), uid=coalesce($2, uid), gid=coalesce($3, gid),
+                atime=coalesce($4, atime), mtime=coalesce($5, mtime), ctime=$6
+                where mnt_pt=$7 and ino=$8";
+        let mut conn = self.connect()?;
+
+        let atime = changes.atime.as_ref().map(timespec_to_ns).transpose()?;
+        let mtime = changes.mtime.as_ref().map(timespec_to_ns).transpose()?;
+        let ctime = timespec_to_ns(&changes.ctime)?;
+
+        Ok(conn.execute(
+            sql,
+            &[
+                &changes.mode,
+                &changes.uid,
+                &changes.gid,
+                &atime,
+                &mtime,
+                &ctime,
+                &mnt_pt,
+                &ino,
+            ],
+        )?)
+    }
+
+    /// Sets the access time on read using relatime rules, only when the
+    /// access time is older than the last modification or change, or a day old
+    pub fn touch_atime(&mut self, file_id: &i64, now: &Timespec) -> PgDbFsResult<u64> {
+        let mut conn = self.connect()?;
+
+        let sql = "update pgdbfs set atime=$2 where id=$1
+                and (atime <= mtime or atime <= ctime or atime < $2 - 86400000000000)";
+        let now = timespec_to_ns(now)?;
+
+        Ok(conn.execute(sql, &[file_id, &now])?)
+    }
+
+    /// Sets the modification and change times of directories whose entries
+    /// changed
+    pub fn touch_dirs(
+        &mut self,
+        mnt_pt: &String,
+        inos: &[i64],
+        now: &Timespec,
+    ) -> PgDbFsResult<u64> {
+        let mut conn = self.connect()?;
+
+        let sql = "update pgdbfs set mtime=$3, ctime=$3 where mnt_pt=$1 and ino=any($2)";
+        let now = timespec_to_ns(now)?;
+
+        Ok(conn.execute(sql, &[mnt_pt, &inos, &now])?)
+    }
+
     /// Looks up entry for the given mount point and parent inode and file name
     ///
-    pub fn lookup(&mut self, mnt_pt: &String, ino: i64, name: &str) -> Option<Ent> {
-        let mut conn = self.connect();
-
-        let sql = "select p.*, (select count(*)::int8 from pgdbfs where parentid=p.ino and is_dir=true) as child_count from pgdbfs p where mnt_pt=$1 and parentid=$2 and name=$3";
+    pub fn lookup(&mut self, mnt_pt: &String, ino: i64, name: &OsStr) -> PgDbFsResult<Ent> {
+        check_name(name)?;
+        let mut conn = self.connect()?;
+
+        let sql = format!(
+            "select p.*, e.id as dentry_id, e.name, {} from pgdbfs_dentry e join pgdbfs p on p.id=e.fsid
+            where e.mnt_pt=$1 and e.parentid=$2 and e.name=$3",
+            NLINK_SQL
+        );
 
         debug!("lookup(sql: {}", sql);
 
-        let row_data = conn.query_one(sql, &[&mnt_pt, &ino, &name]);
-
-        match row_data {
-            Ok(row) => {
-                let c: DateTime<chrono::offset::Utc> = row.get("create_ts");
-                let u: DateTime<chrono::offset::Utc> = row.get("update_ts");
-                let e = Ent {
-                    id: row.get("id"),
-                    ino: row.get("ino"),
-                    name: row.get("name"),
-                    is_dir: row.get("is_dir"),
-                    size: row.get("size"),
-                    segment_len: row.get("segment_len"),
-                    create_ts: c,
-                    update_ts: u,
-                    nlink: row.get("child_count"),
-                };
-                Some(e)
-            }
-            Err(_err) => None,
+        match conn.query_opt(sql.as_str(), &[&mnt_pt, &ino, &name.as_bytes()])? {
+            Some(row) => Ok(make_ent(&row)),
+            None => Err(PgDbFsError::NotFound),
         }
     }
 
     /// Looks up an entry by a specific inode number for the given mount point
     ///
-    pub fn lookup_by_ino(&mut self, mnt_pt: &String, ino: i64) -> Option<Ent> {
-        let mut conn = self.connect();
+    pub fn lookup_by_ino(&mut self, mnt_pt: &String, ino: i64) -> PgDbFsResult<Ent> {
+        let mut conn = self.connect()?;
 
-        let sql = "select p.*, (select count(*)::int8 from pgdbfs where parentid=p.ino and is_dir=true) as child_count from pgdbfs p where mnt_pt=$1 and ino=$2";
+        let sql = format!(
+            "select p.*, {} from pgdbfs p where mnt_pt=$1 and ino=$2",
+            NLINK_SQL
+        );
 
         debug!(
             "lookup_by_ino(sql: {}, mnt_pt: {}, ino: {})",
             sql, mnt_pt, ino
         );
 
-        let row_data = &conn.query_one(sql, &[&mnt_pt, &ino]);
-
-        match row_data {
-            Ok(row) => {
-                let c: DateTime<chrono::offset::Utc> = row.get("create_ts");
-                let u: DateTime<chrono::offset::Utc> = row.get("update_ts");
-                let e = Ent {
-                    id: row.get("id"),
-                    ino: row.get("ino"),
-                    name: row.get("name"),
-                    is_dir: row.get("is_dir"),
-                    size: row.get("size"),
-                    segment_len: row.get("segment_len"),
-                    create_ts: c,
-                    update_ts: u,
-                    nlink: row.get("child_count"),
-                };
+        match conn.query_opt(sql.as_str(), &[&mnt_pt, &ino])? {
+            Some(row) => {
+                let e = make_ent(&row);
                 debug!(
-                    "lookup_by_ino(mnt: {}, ino: {}, id: {}, name: {}, sz: {}",
-                    mnt_pt, ino, e.id, e.name, e.size
+                    "lookup_by_ino(mnt: {}, ino: {}, id: {}, sz: {}",
+                    mnt_pt, ino, e.id, e.size
                 );
-                Some(e)
+                Ok(e)
             }
-            Err(_err) => None,
+            None => Err(PgDbFsError::NotFound),
         }
     }
 
-    pub fn ls(&mut self, mnt_pt: String, ino: i64, offset: i64) -> Vec<Ent> {
-        let mut conn = self.connect();
+    /// Lists up to limit entries of a directory with their attributes whose
+    /// dentry id is greater than after_id, ordered by dentry id. Paging on
+    /// the id keeps a listing stable while entries are created or removed.
+    pub fn ls(
+        &mut self,
+        mnt_pt: &String,
+        ino: i64,
+        after_id: i64,
+        limit: i64,
+    ) -> PgDbFsResult<Vec<Ent>> {
+        let mut conn = self.connect()?;
         let mut v: Vec<Ent> = Vec::new();
-        let sql = "select id, name, is_dir, ino, size, segment_len, create_ts, update_ts from pgdbfs where mnt_pt=$1 and parentid=$2 order by id offset $3 limit 100";
-
-        for row in &conn.query(sql, &[&mnt_pt, &ino, &offset]).unwrap() {
-            let c: DateTime<chrono::offset::Utc> = row.get("create_ts");
-            let u: DateTime<chrono::offset::Utc> = row.get("update_ts");
-            let e = Ent {
-                id: row.get(0),
-                name: row.get(1),
-                is_dir: row.get(2),
-                ino: row.get(3),
-                size: row.get(4),
-                segment_len: row.get(5),
-                create_ts: c,
-                update_ts: u,
-                nlink: 0,
-            };
-            v.push(e)
+        let sql = format!(
+            "select p.*, e.id as dentry_id, e.name, {} from pgdbfs_dentry e join pgdbfs p on p.id=e.fsid
+            where e.mnt_pt=$1 and e.parentid=$2 and e.id>$3 order by e.id limit $4",
+            NLINK_SQL
+        );
+
+        for row in &conn.query(sql.as_str(), &[mnt_pt, &ino, &after_id, &limit])? {
+            v.push(make_ent(row))
         }
         debug!("ls found: {} entries", v.len());
-        v
+        Ok(v)
     }
 
-    pub fn load_segment(&mut self, file_id: &i64, segment_no: &i64) -> Option<Vec<u8>> {
-        let mut conn = self.connect();
-        let sql = "select m.id, m.size, m.is_dir, d.data, d.segment_no from pgdbfs m left join 
-                (select data, fsid, segment_no from pgdbfs_data where fsid=$1 and segment_no=$2) d 
-                on m.id=d.fsid where m.id=$1";
+    /// Loads the segments from first to last that have been written, ordered
+    /// by segment number
+    pub fn load_segments(
+        &mut self,
+        file_id: &i64,
+        first: &i64,
+        last: &i64,
+    ) -> PgDbFsResult<Vec<(i64, Vec<u8>)>> {
+        let mut conn = self.connect()?;
+        let sql = "select segment_no, data from pgdbfs_data where fsid=$1 and segment_no between $2 and $3
+                order by segment_no";
+
+        debug!(
+            "load_segments(file_id: {}, segments: {} - {})",
+            file_id, first, last
+        );
+        let rows = conn.query(sql, &[file_id, first, last])?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("segment_no"), row.get("data")))
+            .collect())
+    }
+
+    /// Loads the data stored for a segment, None if the segment has never
+    /// been written (hole or past the end of the file)
+    pub fn load_segment(
+        &mut self,
+        file_id: &i64,
+        segment_no: &i64,
+    ) -> PgDbFsResult<Option<Vec<u8>>> {
+        let mut conn = self.connect()?;
+        let sql = "select data from pgdbfs_data where fsid=$1 and segment_no=$2";
 
         debug!(
             "load_segment(file_id: {}, segment_no: {}, sql: {})",
             file_id, segment_no, sql
         );
-        let row_data = conn.query_one(sql, &[file_id, segment_no]);
-        match row_data {
-            Ok(row) => {
-                let sz: i64 = row.get("size");
-                if sz == 0 {
-                    Some(Vec::new())
-                } else {
-                    Some(row.get("data"))
-                }
-            }
-            Err(_err) => {
-                error!("Failed sql {}", _err);
-                None
-            }
-        }
+        let row_data = conn.query_opt(sql, &[file_id, segment_no])?;
+        Ok(row_data.map(|row| row.get("data")))
     }
 
-    pub fn writep(&mut self, file_id: &i64, segment_no: &i64, data: &Vec<u8>) -> u64 {
-        let mut conn = self.connect();
-
-        let sql = "insert into pgdbfs_data (id, fsid, segment_no, data) values
-                           ( (select nextval('fsid_seq')), $1, $2, $3) on conflict on constraint pgdbfs_data_uk do update set data=$3";
+    /// Upserts the given segments with one statement, grows the file size
+    /// and sets the modification time, all in one transaction
+    pub fn writep_segments(
+        &mut self,
+        file_id: &i64,
+        segments: &[(i64, &[u8])],
+        mtime: &Timespec,
+    ) -> PgDbFsResult<()> {
+        let mtime = timespec_to_ns(mtime)?;
+        let ctime = timespec_to_ns(&time::get_time())?;
+
+        self.atomically(|tx| upsert_segments(tx, file_id, segments, mtime, ctime))
+    }
 
-        match conn.execute(sql, &[&file_id, &segment_no, &data]) {
-            Result::Ok(val) => {
-                self.update_file_sz(file_id, data.len() as i64);
-                val
-            }
-            Result::Err(err) => {
-                debug!("Failed to write for file_id: {}, reason: {}", file_id, err);
-                0
-            }
-        }
+    /// Like writep_segments, but the transaction is committed durably before
+    /// returning
+    pub fn write_segments(
+        &mut self,
+        file_id: &i64,
+        segments: &[(i64, &[u8])],
+        mtime: &Timespec,
+    ) -> PgDbFsResult<()> {
+        let mtime = timespec_to_ns(mtime)?;
+        let ctime = timespec_to_ns(&time::get_time())?;
+
+        self.atomically(|tx| {
+            tx.batch_execute("set local synchronous_commit = on")?;
+            upsert_segments(tx, file_id, segments, mtime, ctime)
+        })
     }
 
-    pub fn check_segment_exists(&mut self, file_id: &i64, segment_no: &i64) -> bool {
-        let mut conn = self.connect();
+    pub fn check_segment_exists(&mut self, file_id: &i64, segment_no: &i64) -> PgDbFsResult<bool> {
+        let mut conn = self.connect()?;
         let sql = "select count(*)::int as cnt from pgdbfs_data where fsid=$1 and segment_no=$2";
 
-        let row_data = conn.query_one(sql, &[file_id, segment_no]);
-        match row_data {
-            Ok(row) => {
-                let count: i32 = row.get("cnt");
-                if count == 0 {
-                    return false;
-                }
-                true
-            }
-            Err(_err) => false,
-        }
+        let row = conn.query_one(sql, &[file_id, segment_no])?;
+        let count: i32 = row.get("cnt");
+        Ok(count != 0)
     }
 
-    pub fn clear_file_data(&mut self, file_id: &i64) -> bool {
-        let mut conn = self.connect();
+    pub fn clear_file_data(&mut self, file_id: &i64) -> PgDbFsResult<()> {
+        let mut conn = self.connect()?;
         let sql = "delete from pgdbfs_data where fsid=$1";
         debug!("clear_data_for_file(file_id: {})", file_id);
-        match conn.execute(sql, &[file_id]) {
-            Result::Ok(_val) => true,
-            Result::Err(_err) => false,
-        }
+        conn.execute(sql, &[file_id])?;
+        Ok(())
     }
 
-    fn update_file_sz(&mut self, id: &i64, fsize: i64) -> bool {
-        let mut conn = self.connect();
+    /// Truncates or extends the file to size. Segments past the new end are
+    /// deleted and the boundary segment is shortened, an extended range has no
+    /// stored data and reads back as zeros.
+    pub fn truncate(&mut self, file_id: &i64, size: i64, now: &Timespec) -> PgDbFsResult<()> {
+        debug!("truncate(file_id: {}, size: {})", file_id, size);
+
+        let del_sql = "delete from pgdbfs_data d using pgdbfs p
+                where d.fsid=p.id and p.id=$1 and d.segment_no * p.segment_len >= $2";
+        let cut_sql = "update pgdbfs_data d
+                set data=substring(d.data from 1 for ($2 - d.segment_no * p.segment_len)::int)
+                from pgdbfs p
+                where d.fsid=p.id and p.id=$1 and d.segment_no * p.segment_len < $2
+                and d.segment_no * p.segment_len + length(d.data) > $2";
+        let sz_sql = "update pgdbfs set size=$2, mtime=$3, ctime=$3 where id=$1";
+        let now = timespec_to_ns(now)?;
+
+        self.atomically(|tx| {
+            tx.execute(del_sql, &[file_id, &size])?;
+            tx.execute(cut_sql, &[file_id, &size])?;
+            tx.execute(sz_sql, &[file_id, &size, &now])?;
+            Ok(())
+        })
+    }
 
-        let sql = "update pgdbfs set size=size + $1 where id=$2";
+    /// Grows the size of every file on the mount point that ends before its
+    /// highest stored byte, returns the number of files corrected. A size
+    /// past the stored data is left alone, truncate extends files that way,
+    /// unless shrink is set, which cuts every size to the highest stored byte.
+    pub fn recompute_sizes(&mut self, mnt_pt: &String, shrink: bool) -> PgDbFsResult<u64> {
+        let mut conn = self.connect()?;
+
+        let sql = format!(
+            "update pgdbfs p set size=d.sz from (
+                select p2.id, coalesce(max(d2.segment_no * p2.segment_len + length(d2.data)), 0) as sz
+                from pgdbfs p2 left join pgdbfs_data d2 on d2.fsid=p2.id
+                where p2.mnt_pt=$1 and p2.kind='f' group by p2.id) d
+                where p.id=d.id and p.size{}d.sz",
+            if shrink { "<>" } else { "<" }
+        );
 
-        match conn.execute(sql, &[&fsize, &id]) {
-            Result::Ok(_val) => true,
-            Result::Err(_err) => false,
-        }
+        Ok(conn.execute(sql.as_str(), &[mnt_pt])?)
     }
 
-    pub fn has_children(&mut self, file_id: &i64) -> bool {
-        let mut conn = self.connect();
+    pub fn has_children(&mut self, file_id: &i64) -> PgDbFsResult<bool> {
+        let mut conn = self.connect()?;
 
-        let sql = "select count(*)::int as cnt from pgdbfs where parentid=$1";
-
-        let row_data = conn.query_one(sql, &[file_id]);
-        match row_data {
-            Ok(row) => {
-                let count: i32 = row.get("cnt");
-                debug!("has_children(file_id: {}, cnt: {})", file_id, count);
+        let sql = "select count(*)::int as cnt from pgdbfs_dentry where parentid=$1";
 
-                if count == 0 {
-                    return false;
-                }
-                true
-            }
-            Err(_err) => false,
-        }
+        let row = conn.query_one(sql, &[file_id])?;
+        let count: i32 = row.get("cnt");
+        debug!("has_children(file_id: {}, cnt: {})", file_id, count);
+        Ok(count != 0)
     }
     /*
     pub fn num_children(&mut self, file_id: &i64, children_type: Option<fuse::FileType>) -> bool {
@@ -356,50 +842,200 @@ impl PgDbMgr {
         }
     }*/
 
-    pub fn update_parent(&mut self, file_id: &i64, parent_id: &i64) -> bool {
-        let mut conn = self.connect();
+    /// Moves a directory entry under a new parent and name, touching the
+    /// change time of the inode it refers to
+    pub fn move_dentry(
+        &mut self,
+        dentry_id: &i64,
+        parent_id: &i64,
+        name: &OsStr,
+        now: &Timespec,
+    ) -> PgDbFsResult<()> {
+        check_name(name)?;
+        let mut conn = self.connect()?;
+
+        let sql =
+            "with e as (update pgdbfs_dentry set parentid=$1, name=$2 where id=$3 returning fsid)
+                update pgdbfs p set ctime=$4 from e where p.id=e.fsid";
+        let now = timespec_to_ns(now)?;
 
-        let sql = "update pgdbfs set parentid=$1 where id=$2";
+        match conn.execute(sql, &[parent_id, &name.as_bytes(), dentry_id, &now])? {
+            1 => Ok(()),
+            _ => Err(PgDbFsError::NotFound),
+        }
+    }
 
-        let updt_cnt = conn.execute(sql, &[parent_id, file_id]).unwrap();
+    /// Returns the inode of the directory holding a directory, the root is
+    /// its own parent
+    pub fn parent_ino(&mut self, mnt_pt: &String, ino: i64) -> PgDbFsResult<i64> {
+        let mut conn = self.connect()?;
 
-        return updt_cnt == 1;
+        let sql = "select e.parentid from pgdbfs_dentry e join pgdbfs p on p.id=e.fsid
+                where e.mnt_pt=$1 and p.mnt_pt=$1 and p.ino=$2 limit 1";
+
+        let row = conn.query_opt(sql, &[mnt_pt, &ino])?;
+        Ok(row.map_or(ino, |row| row.get("parentid")))
     }
 
-    pub fn update_name(&mut self, file_id: &i64, name: &str) -> bool {
-        let mut conn = self.connect();
+    /// Returns true if the directory ancestor is ino itself or one of the
+    /// directories above it
+    pub fn is_ancestor(&mut self, mnt_pt: &String, ancestor: i64, ino: i64) -> PgDbFsResult<bool> {
+        let mut conn = self.connect()?;
+
+        let sql = "with recursive a(ino) as (
+                    select $2::bigint
+                    union
+                    select e.parentid from pgdbfs_dentry e
+                    join pgdbfs p on p.id=e.fsid
+                    join a on p.ino=a.ino
+                    where e.mnt_pt=$1 and p.mnt_pt=$1 and p.kind='d'
+                )
+                select exists (select 1 from a where ino=$3) as found";
+
+        let row = conn.query_one(sql, &[mnt_pt, &ino, &ancestor])?;
+        Ok(row.get("found"))
+    }
+
+    /// Swaps the inodes two directory entries refer to, touching the change
+    /// time of both inodes
+    pub fn exchange_dentries(
+        &mut self,
+        dentry_a: &i64,
+        dentry_b: &i64,
+        now: &Timespec,
+    ) -> PgDbFsResult<()> {
+        let mut conn = self.connect()?;
+
+        // Counts the swapped entries rather than the touched inodes, two
+        // hard links of one inode touch a single inode
+        let sql = "with o as (select id, fsid from pgdbfs_dentry where id in ($1::int8, $2::int8)),
+                e as (update pgdbfs_dentry d set fsid=o.fsid from o
+                    where d.id in ($1::int8, $2::int8) and o.id<>d.id returning d.fsid),
+                t as (update pgdbfs p set ctime=$3 where p.id in (select fsid from e))
+                select count(*)::int as cnt from e";
+        let now = timespec_to_ns(now)?;
+
+        let row = conn.query_one(sql, &[dentry_a, dentry_b, &now])?;
+        let count: i32 = row.get("cnt");
+        match count {
+            2 => Ok(()),
+            _ => Err(PgDbFsError::NotFound),
+        }
+    }
+
+    /// Removes a directory entry, the inode and its data are deleted with the
+    /// last entry referencing it. Returns the number of inodes deleted.
+    /// Touches the parent directory and the change time of a surviving inode.
+    pub fn unlink(&mut self, dentry_id: &i64, now: &Timespec) -> PgDbFsResult<u64> {
+        let mut conn = self.connect()?;
+
+        let sql = "with d as (delete from pgdbfs_dentry where id=$1 returning fsid, mnt_pt, parentid),
+                t as (update pgdbfs p set mtime=$2, ctime=$2 from d where p.mnt_pt=d.mnt_pt and p.ino=d.parentid),
+                c as (update pgdbfs p set ctime=$2 from d where p.id=d.fsid
+                and exists (select 1 from pgdbfs_dentry e where e.fsid=d.fsid and e.id<>$1))
+                delete from pgdbfs p using d where p.id=d.fsid
+                and not exists (select 1 from pgdbfs_dentry e where e.fsid=d.fsid and e.id<>$1)";
+        let now = timespec_to_ns(now)?;
 
-        let sql = "update pgdbfs set name=$1 where id=$2";
+        let updt_cnt = conn.execute(sql, &[dentry_id, &now])?;
 
-        let updt_cnt = conn.execute(sql, &[&name, file_id]).unwrap();
+        debug!("unlink(dentry_id: {}, inodes: {})", dentry_id, updt_cnt);
 
-        return updt_cnt == 1;
+        Ok(updt_cnt)
     }
 
-    pub fn delete_entity(&mut self, file_id: &i64) -> u64 {
-        let mut conn = self.connect();
+    pub fn get_xattr(
+        &mut self,
+        mnt_pt: &String,
+        ino: i64,
+        name: &OsStr,
+    ) -> PgDbFsResult<Option<Vec<u8>>> {
+        let mut conn = self.connect()?;
+
+        let sql = "select x.value from pgdbfs_xattr x join pgdbfs p on p.id=x.fsid
+                where p.mnt_pt=$1 and p.ino=$2 and x.name=$3";
 
-        let sql = "delete from pgdbfs where id=$1";
+        let row_data = conn.query_opt(sql, &[mnt_pt, &ino, &name.as_bytes()])?;
+        Ok(row_data.map(|row| row.get("value")))
+    }
 
-        let updt_cnt = conn.execute(sql, &[file_id]).unwrap();
+    pub fn list_xattr(&mut self, mnt_pt: &String, ino: i64) -> PgDbFsResult<Vec<OsString>> {
+        let mut conn = self.connect()?;
 
-        debug!("delete_entity(file_id: {})", file_id);
+        let sql = "select x.name from pgdbfs_xattr x join pgdbfs p on p.id=x.fsid
+                where p.mnt_pt=$1 and p.ino=$2 order by x.name";
 
-        return updt_cnt;
+        let rows = conn.query(sql, &[mnt_pt, &ino])?;
+        Ok(rows
+            .iter()
+            .map(|row| OsString::from_vec(row.get("name")))
+            .collect())
     }
 
-    pub fn get_file_sz(&mut self, file_id: &i64) -> i64 {
-        let mut conn = self.connect();
+    /// Sets an extended attribute. With XATTR_CREATE an existing attribute is
+    /// left alone and with XATTR_REPLACE a missing one is not created, in both
+    /// cases 0 is returned.
+    pub fn set_xattr(
+        &mut self,
+        mnt_pt: &String,
+        ino: i64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+    ) -> PgDbFsResult<u64> {
+        let mut conn = self.connect()?;
+
+        let sql = if flags & XATTR_CREATE != 0 {
+            "insert into pgdbfs_xattr (fsid, name, value)
+                select id, $3, $4 from pgdbfs where mnt_pt=$1 and ino=$2
+                on conflict on constraint pgdbfs_xattr_pk do nothing"
+        } else if flags & XATTR_REPLACE != 0 {
+            "update pgdbfs_xattr x set value=$4 from pgdbfs p
+                where p.id=x.fsid and p.mnt_pt=$1 and p.ino=$2 and x.name=$3"
+        } else {
+            "insert into pgdbfs_xattr (fsid, name, value)
+                select id, $3, $4 from pgdbfs where mnt_pt=$1 and ino=$2
+                on conflict on constraint pgdbfs_xattr_pk do update set value=$4"
+        };
+
+        Ok(conn.execute(sql, &[mnt_pt, &ino, &name.as_bytes(), &value])?)
+    }
+
+    pub fn remove_xattr(&mut self, mnt_pt: &String, ino: i64, name: &OsStr) -> PgDbFsResult<u64> {
+        let mut conn = self.connect()?;
+
+        let sql = "delete from pgdbfs_xattr x using pgdbfs p
+                where p.id=x.fsid and p.mnt_pt=$1 and p.ino=$2 and x.name=$3";
+
+        Ok(conn.execute(sql, &[mnt_pt, &ino, &name.as_bytes()])?)
+    }
+
+    /// Aggregates file sizes and inode count for the mount point along with
+    /// the size of the database holding it
+    pub fn fs_usage(&mut self, mnt_pt: &String) -> PgDbFsResult<FsUsage> {
+        let mut conn = self.connect()?;
+
+        let sql = "select coalesce(sum(size) filter (where kind='f'), 0)::int8 as used_bytes,
+                count(*)::int8 as used_inodes,
+                pg_database_size(current_database())::int8 as db_size
+                from pgdbfs where mnt_pt=$1";
+
+        let row = conn.query_one(sql, &[mnt_pt])?;
+        Ok(FsUsage {
+            used_bytes: row.get("used_bytes"),
+            used_inodes: row.get("used_inodes"),
+            db_size: row.get("db_size"),
+        })
+    }
+
+    pub fn get_file_sz(&mut self, file_id: &i64) -> PgDbFsResult<i64> {
+        let mut conn = self.connect()?;
 
         let sql = "select size from pgdbfs where id=$1";
 
-        let row_data = conn.query_one(sql, &[file_id]);
-        match row_data {
-            Ok(row) => {
-                let count: i64 = row.get("size");
-                return count;
-            }
-            Err(_err) => -1,
+        match conn.query_opt(sql, &[file_id])? {
+            Some(row) => Ok(row.get("size")),
+            None => Err(PgDbFsError::NotFound),
         }
     }
 }
@@ -1,16 +1,24 @@
-use db::PgDbMgr;
+extern crate time;
+
+use self::time::Timespec;
+
+use db::{Ent, PgDbFsResult, PgDbMgr};
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::iter::FromIterator;
 
 static TAG: &str = "FCache";
 
+/// Segments a buffer collects beyond the two being written before they are
+/// written out together
+const WRITE_BATCH: usize = 8;
+
 #[derive(Debug)]
 pub struct FSegment {
     pub file_id: i64,
     pub segment_no: i64,
     pub data: Vec<u8>,
+    pub dirty: bool,
 }
 
 impl fmt::Display for FSegment {
@@ -31,6 +39,7 @@ impl FSegment {
             file_id: id,
             segment_no: sno,
             data: Vec::with_capacity(len as usize),
+            dirty: false,
         }
     }
 
@@ -39,136 +48,367 @@ impl FSegment {
     }
 }
 
+#[derive(Debug)]
+struct CachedSegment {
+    data: Vec<u8>,
+    /// Value of the use counter when the segment was last used
+    tick: u64,
+}
+
+/// Clean segment data of all open files kept within a memory budget, the
+/// least recently used segments are evicted first
+#[derive(Debug)]
+pub struct SegmentCache {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    segments: HashMap<(i64, i64), CachedSegment>,
+    /// Cached segments keyed by their last use
+    lru: BTreeMap<u64, (i64, i64)>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl fmt::Display for SegmentCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Cached segments: {}, bytes: {}/{}, hits: {}, misses: {}",
+            self.segments.len(),
+            self.used,
+            self.budget,
+            self.hits,
+            self.misses
+        )
+    }
+}
+
+impl SegmentCache {
+    pub fn new(budget: usize) -> SegmentCache {
+        SegmentCache {
+            budget: budget,
+            used: 0,
+            tick: 0,
+            segments: HashMap::new(),
+            lru: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns the cached data of a segment, marking it most recently used
+    pub fn get(&mut self, file_id: i64, segment_no: i64) -> Option<&[u8]> {
+        self.tick += 1;
+        match self.segments.get_mut(&(file_id, segment_no)) {
+            Some(s) => {
+                self.lru.remove(&s.tick);
+                s.tick = self.tick;
+                self.lru.insert(s.tick, (file_id, segment_no));
+                self.hits += 1;
+                Some(&s.data)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn contains(&self, file_id: i64, segment_no: i64) -> bool {
+        self.segments.contains_key(&(file_id, segment_no))
+    }
+
+    /// Removes a segment from the cache and hands its data to a writer
+    pub fn take(&mut self, file_id: i64, segment_no: i64) -> Option<Vec<u8>> {
+        match self.remove(file_id, segment_no) {
+            Some(data) => {
+                self.hits += 1;
+                Some(data)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches clean segment data, evicting the least recently used segments
+    /// to stay within the budget. Data larger than the budget is not cached.
+    pub fn insert(&mut self, file_id: i64, segment_no: i64, data: Vec<u8>) {
+        self.remove(file_id, segment_no);
+        if data.len() > self.budget {
+            return;
+        }
+        while self.used + data.len() > self.budget {
+            let key = match self.lru.values().next() {
+                Some(key) => *key,
+                None => break,
+            };
+            self.remove(key.0, key.1);
+        }
+        self.tick += 1;
+        self.used += data.len();
+        self.lru.insert(self.tick, (file_id, segment_no));
+        self.segments.insert(
+            (file_id, segment_no),
+            CachedSegment {
+                data: data,
+                tick: self.tick,
+            },
+        );
+    }
+
+    /// Drops every cached segment of a file
+    pub fn invalidate_file(&mut self, file_id: i64) {
+        let keys: Vec<(i64, i64)> = self
+            .segments
+            .keys()
+            .filter(|key| key.0 == file_id)
+            .cloned()
+            .collect();
+        for (file_id, segment_no) in keys {
+            self.remove(file_id, segment_no);
+        }
+    }
+
+    fn remove(&mut self, file_id: i64, segment_no: i64) -> Option<Vec<u8>> {
+        let s = self.segments.remove(&(file_id, segment_no))?;
+        self.lru.remove(&s.tick);
+        self.used -= s.data.len();
+        Some(s.data)
+    }
+}
+
+/// Appends size bytes of segment data starting at offset to out, zero
+/// filling what the segment does not hold
+fn copy_range(data: &[u8], offset: usize, size: usize, out: &mut Vec<u8>) {
+    let avail = cmp::min(data.len().saturating_sub(offset), size);
+    if avail > 0 {
+        out.extend_from_slice(&data[offset..offset + avail]);
+    }
+    out.resize(out.len() + size - avail, 0);
+}
+
+/// Open file, segments holds the few segments being written while segments
+/// only read are kept in the shared SegmentCache
 #[derive(Debug)]
 pub struct FBuffer {
     pub file_id: i64,
     pub segment_len: i32,
     pub segments: Vec<FSegment>,
-    pub flags: u32,
+    pub size: i64,
+    /// Modification time of the last write, saved with the dirty segments
+    pub mtime: Timespec,
+    /// Number of open handles sharing this buffer
+    pub refs: u32,
 }
 
 impl fmt::Display for FBuffer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "File: {}, num_segments: {}",
+            "File: {}, num_segments: {}, size: {}",
             self.file_id,
-            self.segments.len()
+            self.segments.len(),
+            self.size
         )
     }
 }
 
 impl FBuffer {
-    pub fn new(id: i64, slen: i32, flags: u32) -> FBuffer {
+    pub fn new(id: i64, slen: i32, size: i64, mtime: Timespec) -> FBuffer {
         FBuffer {
             file_id: id,
             segment_len: slen,
             segments: Vec::new(),
-            flags: flags,
+            size: size,
+            mtime: mtime,
+            refs: 0,
         }
     }
 
-    pub fn add(&mut self, offset: i64, data: &[u8], db: &mut PgDbMgr) -> i32 {
-        let segment_no_for_offset = self.get_segment_no(offset);
+    /// Writes data at the given offset, splicing it into the segments that
+    /// cover the range. Segments are loaded from db or created as needed, gaps
+    /// before the offset are zero filled and the file is extended if the write
+    /// ends past the current size.
+    pub fn add(
+        &mut self,
+        offset: i64,
+        data: &[u8],
+        mtime: Timespec,
+        cache: &mut SegmentCache,
+        db: &mut PgDbMgr,
+    ) -> PgDbFsResult<()> {
         debug!(
-            "** {} add(segment_no_for_offset: {} = {})",
-            TAG, offset, segment_no_for_offset
-        );
-        if db.check_segment_exists(&self.file_id, &segment_no_for_offset) {
-            self.get_or_load_segment(&segment_no_for_offset, db);
-        }
-
-        if self.segments.is_empty() {
-            let seg = FSegment::new(self.file_id, segment_no_for_offset, self.segment_len);
-            self.segments.push(seg);
-        } else if self.segments.last().unwrap().len() == self.segment_len as usize {
-            let seg_no = self.segments.last().unwrap().segment_no + 1;
-            let seg = FSegment::new(self.file_id, seg_no, self.segment_len);
-            self.segments.push(seg);
-        }
-
-        let last_seg_space = cmp::min(
-            self.segment_len as usize - self.segments.last().unwrap().len(),
-            data.len(),
+            "** {} add(id: {}, offset: {}, len: {})",
+            TAG,
+            self.file_id,
+            offset,
+            data.len()
         );
+        let mut offset_t: i64 = offset;
+        let mut rem: &[u8] = data;
+        while !rem.is_empty() {
+            let seg_no = self.get_segment_no(offset_t);
+            let offset_in_seg = (offset_t - seg_no * self.segment_len as i64) as usize;
+            let size_in_seg = cmp::min(self.segment_len as usize - offset_in_seg, rem.len());
+
+            let segment_idx = self.get_or_load_segment(&seg_no, cache, db)?;
+            let segment = &mut self.segments[segment_idx as usize];
+            if segment.len() < offset_in_seg + size_in_seg {
+                segment.data.resize(offset_in_seg + size_in_seg, 0);
+            }
+            segment.data[offset_in_seg..offset_in_seg + size_in_seg]
+                .copy_from_slice(&rem[..size_in_seg]);
+            segment.dirty = true;
 
-        self.segments
-            .last_mut()
-            .unwrap()
-            .data
-            .extend(data[0..last_seg_space].iter().copied());
-
-        let rem = &data[last_seg_space..];
-        let iter = rem.chunks(self.segment_len as usize);
-        for chunk in iter {
-            let seg_no = self.segments.last().unwrap().segment_no + 1;
-            let mut new_seg = FSegment::new(self.file_id, seg_no, self.segment_len);
-            new_seg.data.extend(chunk.to_vec());
-            self.segments.push(new_seg);
+            offset_t += size_in_seg as i64;
+            rem = &rem[size_in_seg..];
         }
-        self.trim_segments(db);
-
-        return 0;
+        self.size = cmp::max(self.size, offset + data.len() as i64);
+        self.mtime = mtime;
+        self.trim_segments(cache, db)
     }
 
-    pub fn trim_segments(&mut self, db: &mut PgDbMgr) {
-        if self.segments.len() > 3 {
+    /// Once WRITE_BATCH segments collected, writes out all but the last two
+    /// segments being written in one statement and moves them to the segment
+    /// cache, segments stay here if writing them failed
+    pub fn trim_segments(
+        &mut self,
+        cache: &mut SegmentCache,
+        db: &mut PgDbMgr,
+    ) -> PgDbFsResult<()> {
+        if self.segments.len() >= WRITE_BATCH + 2 {
             let end = self.segments.len() - 2;
-            let tsegments: Vec<_> = self.segments.drain(0..end).collect();
-            for s in tsegments {
-                db.writep(&self.file_id, &s.segment_no, &s.data);
+            let dirty: Vec<(i64, &[u8])> = self.segments[..end]
+                .iter()
+                .filter(|s| s.dirty)
+                .map(|s| (s.segment_no, s.data.as_slice()))
+                .collect();
+            if !dirty.is_empty() {
+                db.writep_segments(&self.file_id, &dirty, &self.mtime)?;
+            }
+            for s in self.segments.drain(0..end) {
+                cache.insert(self.file_id, s.segment_no, s.data);
             }
         }
+        Ok(())
     }
 
-    pub fn save(&mut self, db: &mut PgDbMgr) -> i64 {
+    /// Writes the dirty segments and the file size in one transaction,
+    /// segments are marked clean only once it committed
+    pub fn save(&mut self, db: &mut PgDbMgr) -> PgDbFsResult<i64> {
         debug!("Save called: {}", self.file_id);
-        let mut total_written: i64 = 0;
-        for s in self.segments.iter() {
-            db.writep(&self.file_id, &s.segment_no, &s.data);
-            total_written += s.len() as i64;
+        let dirty: Vec<(i64, &[u8])> = self
+            .segments
+            .iter()
+            .filter(|s| s.dirty)
+            .map(|s| (s.segment_no, s.data.as_slice()))
+            .collect();
+        if dirty.is_empty() {
+            return Ok(0);
+        }
+        let total_written: i64 = dirty.iter().map(|s| s.1.len() as i64).sum();
+        db.writep_segments(&self.file_id, &dirty, &self.mtime)?;
+        for s in self.segments.iter_mut() {
+            s.dirty = false;
+        }
+        Ok(total_written)
+    }
+
+    /// Writes every dirty segment in a single committed transaction, segments
+    /// are marked clean only once the transaction succeeded
+    pub fn sync(&mut self, db: &mut PgDbMgr) -> PgDbFsResult<()> {
+        debug!("** {} sync(id: {})", TAG, self.file_id);
+        let dirty: Vec<(i64, &[u8])> = self
+            .segments
+            .iter()
+            .filter(|s| s.dirty)
+            .map(|s| (s.segment_no, s.data.as_slice()))
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        db.write_segments(&self.file_id, &dirty, &self.mtime)?;
+        for s in self.segments.iter_mut() {
+            s.dirty = false;
         }
-        return total_written;
+        Ok(())
     }
 
-    pub fn read(&mut self, offset: i64, size: i32, db: &mut PgDbMgr) -> Option<Vec<u8>> {
+    /// Cuts or extends the cached file to size, dropping cached segments past
+    /// the new end and shortening the boundary segment
+    pub fn truncate(&mut self, size: i64, mtime: Timespec, cache: &mut SegmentCache) {
         debug!(
-            "** {} read(id: {} offset = {}, len: {}",
-            TAG, self.file_id, offset, size
+            "** {} truncate(id: {}, size: {} -> {})",
+            TAG, self.file_id, self.size, size
         );
-        let segment_nos = self.get_segment_nos(offset, size);
-        match segment_nos {
-            Some(segs) => {
-                let mut read_data: Vec<u8> = Vec::new();
-                let mut offset_t: i64 = offset;
-                let mut size_t: i32 = size;
-                for i in 0..segs.len() {
-                    let seg_num: i64 = segs[i] as i64;
-
-                    let segment_idx = self.get_or_load_segment(&seg_num, db);
-                    //println!("{}, {}, {}", offset, size, segment_idx);
-                    let segment = &self.segments[segment_idx as usize];
-                    let offset_in_seg: i64 = offset_t - (seg_num * self.segment_len as i64);
-
-                    let size_in_seg: i32 =
-                        cmp::min((segment.len() as i64 - offset_in_seg) as i32, size_t);
-                    debug!(
-                        "** {} read(file_id: {}, seg_idx: {}, offset_in_seg: {}, size_in_seg: {}",
-                        TAG, self.file_id, segs[i], offset_in_seg, size_in_seg
-                    );
-
-                    let t1: usize = offset_in_seg as usize;
-                    let t2: usize = (offset_in_seg + size_in_seg as i64) as usize;
-                    let part = Vec::from_iter(segment.data[t1..t2].iter().cloned());
-
-                    read_data.extend(part);
-                    offset_t += size_in_seg as i64;
-                    size_t -= size_in_seg;
-                }
-                return Some(read_data);
+        cache.invalidate_file(self.file_id);
+        let segment_len = self.segment_len as i64;
+        self.segments.retain(|s| s.segment_no * segment_len < size);
+        for s in self.segments.iter_mut() {
+            let seg_end = (size - s.segment_no * segment_len) as usize;
+            if s.len() > seg_end {
+                s.data.truncate(seg_end);
             }
-            None => None,
         }
+        self.size = size;
+        self.mtime = mtime;
+    }
+
+    /// Reads up to size bytes from offset, never past the end of the file.
+    /// Ranges inside the file that have no stored data read back as zeros.
+    /// Segments missing from the cache are loaded in one query together with
+    /// up to readahead segments following the read.
+    pub fn read(
+        &mut self,
+        offset: i64,
+        size: i32,
+        readahead: u64,
+        cache: &mut SegmentCache,
+        db: &mut PgDbMgr,
+    ) -> PgDbFsResult<Vec<u8>> {
+        debug!(
+            "** {} read(id: {} offset = {}, len: {}, readahead: {}, file_sz: {}",
+            TAG, self.file_id, offset, size, readahead, self.size
+        );
+        let offset_end: i64 = cmp::min(offset + size as i64, self.size);
+        let mut read_data: Vec<u8> = Vec::new();
+        if offset >= offset_end {
+            return Ok(read_data);
+        }
+        let last_seg_no = self.get_segment_no(offset_end - 1);
+        let readahead = cmp::min(readahead, i64::MAX as u64) as i64;
+        let load_end = cmp::min(
+            last_seg_no.saturating_add(readahead),
+            self.get_segment_no(self.size - 1),
+        );
+        let mut offset_t: i64 = offset;
+        while offset_t < offset_end {
+            let seg_num = self.get_segment_no(offset_t);
+            let offset_in_seg = (offset_t - seg_num * self.segment_len as i64) as usize;
+            let size_in_seg = cmp::min(
+                self.segment_len as i64 - offset_in_seg as i64,
+                offset_end - offset_t,
+            ) as usize;
+            debug!(
+                "** {} read(file_id: {}, seg_no: {}, offset_in_seg: {}, size_in_seg: {}",
+                TAG, self.file_id, seg_num, offset_in_seg, size_in_seg
+            );
+
+            let segment_idx = self.get_segment_cache(&seg_num);
+            if segment_idx != -1 {
+                let segment = &self.segments[segment_idx as usize];
+                copy_range(&segment.data, offset_in_seg, size_in_seg, &mut read_data);
+            } else if let Some(data) = cache.get(self.file_id, seg_num) {
+                copy_range(data, offset_in_seg, size_in_seg, &mut read_data);
+            } else {
+                let data = self.load_segments(seg_num, load_end, cache, db)?;
+                copy_range(&data, offset_in_seg, size_in_seg, &mut read_data);
+            }
+            offset_t += size_in_seg as i64;
+        }
+        Ok(read_data)
     }
 
     pub fn get_segment_nos(&mut self, offset: i64, size: i32) -> Option<Vec<i32>> {
@@ -191,44 +431,69 @@ impl FBuffer {
         return offset / self.segment_len as i64;
     }
 
-    fn get_or_load_segment(&mut self, segment_no: &i64, db: &mut PgDbMgr) -> i64 {
+    /// Loads the segments from first to last in one query, caching those
+    /// not cached yet, and returns the data of the first one
+    fn load_segments(
+        &mut self,
+        first: i64,
+        last: i64,
+        cache: &mut SegmentCache,
+        db: &mut PgDbMgr,
+    ) -> PgDbFsResult<Vec<u8>> {
+        debug!(
+            "** {} load_segments(id: {}, segments: {} - {})",
+            TAG, self.file_id, first, last
+        );
+        let mut first_data: Vec<u8> = Vec::new();
+        for (no, data) in db.load_segments(&self.file_id, &first, &last)? {
+            if no == first {
+                first_data = data.clone();
+            }
+            if self.get_segment_cache(&no) == -1 && !cache.contains(self.file_id, no) {
+                cache.insert(self.file_id, no, data);
+            }
+        }
+        // Holes have no row, remember that the first one reads as zeros
+        if first_data.is_empty() {
+            cache.insert(self.file_id, first, Vec::new());
+        }
+        Ok(first_data)
+    }
+
+    fn get_or_load_segment(
+        &mut self,
+        segment_no: &i64,
+        cache: &mut SegmentCache,
+        db: &mut PgDbMgr,
+    ) -> PgDbFsResult<i64> {
         // Check if exists in local cache
         let existing_idx = self.get_segment_cache(segment_no);
         debug!("Existing idx: {}", existing_idx);
         if existing_idx == -1 {
-            match db.load_segment(&self.file_id, segment_no) {
-                Some(bytes) => {
-                    let s = FSegment {
-                        file_id: self.file_id,
-                        segment_no: *segment_no,
-                        data: bytes,
-                    };
-                    self.segments.push(s);
-                }
+            let data = match cache.take(self.file_id, *segment_no) {
+                Some(data) => Some(data),
+                None => db.load_segment(&self.file_id, segment_no)?,
+            };
+            let s = match data {
+                Some(bytes) => FSegment {
+                    file_id: self.file_id,
+                    segment_no: *segment_no,
+                    data: bytes,
+                    dirty: false,
+                },
                 None => {
-                    debug!("Row not found in db");
-                    error!("No segment in db for segment_no: {}", segment_no);
-
-                    let potential_offset = segment_no * self.segment_len as i64;
-
-                    let file_sz = db.get_file_sz(&self.file_id);
-
-                    if potential_offset >= file_sz {
-                        debug!("offset exceeds file size, returning last segment");
-                        return self.segments.len() as i64 - 1;
-                    }
-                    panic!(
-                        "Invalid offset for file: {}, segment_no: {}",
-                        self.file_id, segment_no
-                    );
+                    // Hole or past the end of the file, starts out empty
+                    debug!("No segment in db for segment_no: {}", segment_no);
+                    FSegment::new(self.file_id, *segment_no, self.segment_len)
                 }
-            }
-
-            return self.get_segment_cache(segment_no);
+            };
+            self.segments.push(s);
+            return Ok(self.segments.len() as i64 - 1);
         }
-        existing_idx
+        Ok(existing_idx)
     }
 
+    /// Index of a segment among the few being written, -1 if it is not one
     fn get_segment_cache(&mut self, segment_no: &i64) -> i64 {
         for (i, s) in self.segments.iter().enumerate() {
             if s.segment_no == *segment_no {
@@ -241,14 +506,35 @@ impl FBuffer {
     }
 }
 
+/// State of a single open of a file, handles opened on the same file share
+/// the segment data in its FBuffer
+#[derive(Debug)]
+pub struct FHandle {
+    pub ino: i64,
+    pub flags: u32,
+    /// Offset following the last read or write through this handle
+    pub pos: i64,
+    /// Set when data was written through this handle since the last flush
+    pub dirty: bool,
+    /// Set once the access time was updated for a read through this handle
+    pub accessed: bool,
+}
+
 #[derive(Debug)]
 pub struct FCache {
     pub fcache: HashMap<String, FBuffer>,
+    pub handles: HashMap<u64, FHandle>,
+    next_fh: u64,
 }
 
 impl fmt::Display for FCache {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Cache size: {}", self.fcache.len())
+        write!(
+            f,
+            "Cache size: {}, open handles: {}",
+            self.fcache.len(),
+            self.handles.len()
+        )
     }
 }
 
@@ -256,6 +542,8 @@ impl FCache {
     pub fn new() -> FCache {
         FCache {
             fcache: HashMap::new(),
+            handles: HashMap::new(),
+            next_fh: 1,
         }
     }
 
@@ -264,42 +552,65 @@ impl FCache {
         self.fcache.get_mut(&key)
     }
 
-    pub fn get_cached(
-        &mut self,
-        mnt_pt: &String,
-        ino: i64,
-        flags: u32,
-        db: &mut PgDbMgr,
-    ) -> Option<&mut FBuffer> {
+    pub fn get_handle(&mut self, fh: u64) -> Option<&mut FHandle> {
+        self.handles.get_mut(&fh)
+    }
+
+    /// Allocates a new file handle, sharing the buffer of the file if it is
+    /// already open
+    pub fn open(&mut self, mnt_pt: &String, ent: &Ent, flags: u32) -> u64 {
+        let ino = ent.ino;
+        debug!("Caching file: mnt_pt: {}, ino: {})", mnt_pt, ino);
         let key = self.make_key(mnt_pt, &ino);
-        if self.fcache.contains_key(&key) {
-            return self.fcache.get_mut(&key);
-        }
-        debug!("Cached file not found, looking up in db {}", ino);
-        match db.lookup_by_ino(mnt_pt, ino as i64) {
-            None => {
-                debug!("No entries found for ino: {}", ino);
-                None
-            }
-            Some(ent) => {
-                self.init(mnt_pt, ino, ent.id, flags, ent.segment_len);
-                self.fcache.get_mut(&key)
+        let fb = self
+            .fcache
+            .entry(key)
+            .or_insert_with(|| FBuffer::new(ent.id, ent.segment_len, ent.size, ent.mtime));
+        fb.refs += 1;
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(
+            fh,
+            FHandle {
+                ino: ino,
+                flags: flags,
+                pos: 0,
+                dirty: false,
+                accessed: false,
+            },
+        );
+        debug!("open(ino: {}, fh: {}, refs: {})", ino, fh, fb.refs);
+        fh
+    }
+
+    /// Releases a file handle, returns the inode once the last handle on the
+    /// file is released. The buffer stays cached until removed, so it is not
+    /// lost if saving it fails.
+    pub fn release(&mut self, mnt_pt: &String, fh: u64) -> Option<i64> {
+        let handle = self.handles.remove(&fh)?;
+        let key = self.make_key(mnt_pt, &handle.ino);
+        let refs = match self.fcache.get_mut(&key) {
+            Some(fb) => {
+                fb.refs -= 1;
+                fb.refs
             }
+            None => return None,
+        };
+        debug!("release(ino: {}, fh: {}, refs: {})", handle.ino, fh, refs);
+        if refs == 0 {
+            return Some(handle.ino);
         }
+        None
     }
 
+    /// Removes the buffer of a file no handle refers to anymore
     pub fn remove(&mut self, mnt_pt: &String, ino: &i64) -> Option<FBuffer> {
         let key = self.make_key(mnt_pt, ino);
-        self.fcache.remove(&key)
-    }
-
-    pub fn init(&mut self, mnt_pt: &String, ino: i64, id: i64, flags: u32, segment_len: i32) {
-        debug!("Caching file: mnt_pt: {}, ino: {})", mnt_pt, ino);
-        let key = self.make_key(mnt_pt, &ino);
-        //info!("Key = {}", key);
-        self.fcache
-            .entry(key)
-            .or_insert_with(|| FBuffer::new(id, segment_len, flags));
+        match self.fcache.get(&key) {
+            Some(fb) if fb.refs == 0 => self.fcache.remove(&key),
+            _ => None,
+        }
     }
 
     fn make_key(&mut self, mnt_pt: &String, ino: &i64) -> String {
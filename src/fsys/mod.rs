@@ -1,35 +1,65 @@
-extern crate chrono;
 extern crate dirs;
-extern crate fuse;
+extern crate fuser;
 extern crate libc;
 extern crate rand;
 extern crate time;
 
 use serde::{Deserialize, Serialize};
+use std::cmp;
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use self::chrono::{DateTime, Utc};
-use self::fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, ReplyWrite, Request,
+use self::fuser::{
+    FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
 };
-use self::libc::{c_int, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY};
-use self::libc::{O_ACCMODE, O_APPEND, O_RDONLY, O_RDWR, O_WRONLY};
+use self::libc::{c_int, EACCES, EBADF, EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use self::libc::{ENODATA, ERANGE, F_OK, XATTR_CREATE, XATTR_REPLACE, X_OK};
+use self::libc::{O_ACCMODE, O_APPEND, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+use self::libc::{RENAME_EXCHANGE, RENAME_NOREPLACE};
 use self::time::Timespec;
-use std::time::{Duration, UNIX_EPOCH};
 
 use db;
-use db::PgDbMgr;
+use db::{PgDbFsError, PgDbFsResult, PgDbMgr};
 
 use fcache;
+use icache;
+use icache::CachedLookup;
+
+/// Block size reported by statfs
+const STATFS_BSIZE: u32 = 4096;
+
+/// Number of directory entries readdir fetches per query
+const READDIR_BATCH: i64 = 100;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct PgDbFsConfig {
     pub db_host: String,
     pub db_user: String,
     pub db_pass: String,
     pub db_segment_len: i32,
+    /// Let the kernel enforce the stored mode bits and ownership
+    pub default_permissions: bool,
+    /// Allow users other than the one mounting to access the file system
+    pub allow_other: bool,
+    /// Capacity reported by statfs in bytes, 0 reports the database size
+    pub quota_bytes: i64,
+    /// Number of inodes reported by statfs, 0 for no limit
+    pub quota_inodes: i64,
+    /// Milliseconds the kernel and the inode cache keep a looked up name
+    pub entry_ttl_ms: u64,
+    /// Milliseconds the kernel and the inode cache keep inode attributes
+    pub attr_ttl_ms: u64,
+    /// Milliseconds a name found not to exist is remembered, 0 to disable
+    pub negative_ttl_ms: u64,
+    /// Segments loaded ahead of sequential reads, 0 to disable
+    pub readahead_segments: u64,
+    /// Memory in bytes for caching segments read from open files
+    pub segment_cache_bytes: u64,
 }
 
 impl ::std::default::Default for PgDbFsConfig {
@@ -39,6 +69,15 @@ impl ::std::default::Default for PgDbFsConfig {
             db_user: "pgdbfs".to_string(),
             db_pass: "pgdbfs".to_string(),
             db_segment_len: 1048576,
+            default_permissions: true,
+            allow_other: false,
+            quota_bytes: 0,
+            quota_inodes: 0,
+            entry_ttl_ms: 1000,
+            attr_ttl_ms: 1000,
+            negative_ttl_ms: 0,
+            readahead_segments: 4,
+            segment_cache_bytes: 268435456,
         }
     }
 }
@@ -48,7 +87,12 @@ pub struct PgDbFs {
     mount_pt: String,
     db_mgr: PgDbMgr,
     fcache: fcache::FCache,
+    scache: fcache::SegmentCache,
+    icache: icache::ICache,
     cfg: PgDbFsConfig,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    negative_ttl: Duration,
 }
 
 pub trait DbFsUtils {
@@ -65,73 +109,579 @@ pub trait DbFsUtils {
         }
     }
 
-    fn make_file_entry(&self, ent: &db::Ent, req: &Request) -> FileAttr {
+    fn file_type(&self, kind: db::EntKind) -> FileType {
+        match kind {
+            db::EntKind::Directory => FileType::Directory,
+            db::EntKind::File => FileType::RegularFile,
+            db::EntKind::Symlink => FileType::Symlink,
+        }
+    }
+
+    fn make_file_entry(&self, ent: &db::Ent) -> FileAttr {
         let blocks = self.calculate_num_blocks(ent.size) * 2;
         let attr = FileAttr {
             ino: ent.ino as u64,
             size: ent.size as u64,
             blocks: blocks,
-            atime: Timespec::new(ent.create_ts.timestamp(), 0),
-            mtime: Timespec::new(ent.update_ts.timestamp(), 0),
-            ctime: Timespec::new(ent.update_ts.timestamp(), 0),
-            crtime: Timespec::new(ent.create_ts.timestamp(), 0),
-            kind: if ent.is_dir {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            },
-            perm: 0o644,
-            nlink: if ent.is_dir { 2 + ent.nlink as u32 } else { 1 },
-            uid: req.uid(),
-            gid: req.gid(),
+            atime: timespec_to_system_time(&ent.atime),
+            mtime: timespec_to_system_time(&ent.mtime),
+            ctime: timespec_to_system_time(&ent.ctime),
+            crtime: timespec_to_system_time(&ent.crtime),
+            kind: self.file_type(ent.kind),
+            perm: (ent.mode & 0o7777) as u16,
+            nlink: ent.nlink as u32,
+            uid: ent.uid as u32,
+            gid: ent.gid as u32,
             rdev: 0,
+            blksize: STATFS_BSIZE,
             flags: 0,
         };
         return attr;
     }
+
+    /// Checks the access mask (R_OK, W_OK, X_OK) against the mode bits of the
+    /// entry for the given user. Root may read and write anything and execute
+    /// anything that has an execute bit set.
+    fn check_access(&self, ent: &db::Ent, uid: u32, gid: u32, mask: i32) -> bool {
+        if mask == F_OK {
+            return true;
+        }
+        let mode = ent.mode as u32;
+        if uid == 0 {
+            return mask & X_OK == 0 || ent.is_dir() || mode & 0o111 != 0;
+        }
+        let bits = if uid as i64 == ent.uid {
+            (mode >> 6) & 0o7
+        } else if gid as i64 == ent.gid {
+            (mode >> 3) & 0o7
+        } else {
+            mode & 0o7
+        };
+        (mask as u32) & !bits == 0
+    }
 }
 
 impl DbFsUtils for PgDbFs {}
 
+impl PgDbFs {
+    /// File system for the mount point keeping its files in db_mgr, which
+    /// must be initialized
+    pub fn new(mount_pt: String, cfg: PgDbFsConfig, db_mgr: PgDbMgr) -> PgDbFs {
+        PgDbFs {
+            mount_pt: mount_pt,
+            db_mgr: db_mgr,
+            fcache: fcache::FCache::new(),
+            scache: fcache::SegmentCache::new(
+                cmp::min(cfg.segment_cache_bytes, usize::MAX as u64) as usize
+            ),
+            icache: icache::ICache::new(
+                time::Duration::milliseconds(cfg.entry_ttl_ms as i64),
+                time::Duration::milliseconds(cfg.attr_ttl_ms as i64),
+                time::Duration::milliseconds(cfg.negative_ttl_ms as i64),
+            ),
+            entry_ttl: Duration::from_millis(cfg.entry_ttl_ms),
+            attr_ttl: Duration::from_millis(cfg.attr_ttl_ms),
+            negative_ttl: Duration::from_millis(cfg.negative_ttl_ms),
+            cfg: cfg,
+        }
+    }
+
+    /// Renames an entry for rename and renameat2 in a transaction of its
+    /// own, fails with the errno to reply with
+    pub fn rename_atomically(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        self.db_mgr.begin().map_err(|err| err.errno())?;
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Err(errno) => {
+                self.db_mgr.rollback();
+                Err(errno)
+            }
+            Ok(()) => self.db_mgr.commit().map_err(|err| err.errno()),
+        }
+    }
+
+    /// Size and modification time in db lag behind writes still buffered in
+    /// an open file, report the buffered values while the file is cached
+    fn apply_cached_attrs(&mut self, ent: &mut db::Ent) {
+        if let Some(fb) = self.fcache.get(&self.mount_pt, &ent.ino) {
+            ent.size = fb.size;
+            ent.mtime = fb.mtime;
+            ent.ctime = cmp::max(ent.ctime, fb.mtime);
+        }
+    }
+
+    /// Drops the cached attributes of a directory and its cached entry for
+    /// name, called when the entry is created, removed or replaced
+    fn invalidate_entry(&mut self, parent: u64, name: &OsStr) {
+        self.icache.invalidate_entry(parent as i64, name);
+        self.icache.invalidate(parent as i64);
+    }
+
+    /// Attributes replied for a name that does not exist, inode 0 lets the
+    /// kernel cache the negative lookup for the ttl of the reply
+    fn negative_entry(&self) -> FileAttr {
+        FileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: STATFS_BSIZE,
+            flags: 0,
+        }
+    }
+
+    /// Truncates the file in db and keeps a cached buffer for it consistent
+    fn truncate_file(&mut self, ent: &db::Ent, size: i64) -> PgDbFsResult<()> {
+        let now = time::get_time();
+        self.icache.invalidate(ent.ino);
+        self.db_mgr.truncate(&ent.id, size, &now)?;
+        if let Some(fb) = self.fcache.get(&self.mount_pt, &ent.ino) {
+            fb.truncate(size, now, &mut self.scache);
+        }
+        Ok(())
+    }
+
+    /// Moves an entry under a new parent and name following rename(2) and the
+    /// renameat2 RENAME_NOREPLACE and RENAME_EXCHANGE flags. Runs inside the
+    /// transaction opened by the caller, fails with the errno to reply with.
+    fn rename_entry(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        let noreplace = flags & RENAME_NOREPLACE == RENAME_NOREPLACE;
+        let exchange = flags & RENAME_EXCHANGE == RENAME_EXCHANGE;
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 || (noreplace && exchange) {
+            return Err(EINVAL);
+        }
+        let dst_dir = self
+            .db_mgr
+            .lookup_by_ino(&self.mount_pt, newparent as i64)
+            .map_err(|err| {
+                error!(
+                    "Dst dir lookup failed: mnt: {} ino: {}, reason: {}",
+                    self.mount_pt, newparent, err
+                );
+                err.errno()
+            })?;
+        if !dst_dir.is_dir() {
+            error!(
+                "Dst is not a directory, mnt: {}, ino: {}",
+                self.mount_pt, newparent
+            );
+            return Err(ENOTDIR);
+        }
+        let srcent = self
+            .db_mgr
+            .lookup(&self.mount_pt, parent as i64, name)
+            .map_err(|err| {
+                error!(
+                    "Src file lookup failed, mnt: {}, ino: {}, file: {:?}, reason: {}",
+                    self.mount_pt, parent, name, err
+                );
+                err.errno()
+            })?;
+        self.icache.invalidate(srcent.ino);
+        self.invalidate_entry(parent, name);
+        self.invalidate_entry(newparent, newname);
+        // A directory can not be moved below itself
+        if srcent.is_dir()
+            && self
+                .db_mgr
+                .is_ancestor(&self.mount_pt, srcent.ino, newparent as i64)
+                .map_err(|err| err.errno())?
+        {
+            return Err(EINVAL);
+        }
+        let dst_file = match self
+            .db_mgr
+            .lookup(&self.mount_pt, newparent as i64, newname)
+        {
+            Ok(ent) => Some(ent),
+            Err(PgDbFsError::NotFound) => None,
+            Err(err) => return Err(err.errno()),
+        };
+        let now = time::get_time();
+        match dst_file {
+            None => {
+                if exchange {
+                    return Err(ENOENT);
+                }
+            }
+            Some(dst_file) => {
+                self.icache.invalidate(dst_file.ino);
+                if noreplace {
+                    return Err(EEXIST);
+                }
+                // Both names already refer to the same inode, nothing to do
+                if dst_file.ino == srcent.ino {
+                    return Ok(());
+                }
+                if exchange {
+                    if dst_file.is_dir()
+                        && self
+                            .db_mgr
+                            .is_ancestor(&self.mount_pt, dst_file.ino, parent as i64)
+                            .map_err(|err| err.errno())?
+                    {
+                        return Err(EINVAL);
+                    }
+                    self.db_mgr
+                        .exchange_dentries(&srcent.dentry_id, &dst_file.dentry_id, &now)
+                        .map_err(|err| err.errno())?;
+                    self.db_mgr
+                        .touch_dirs(&self.mount_pt, &[parent as i64, dst_dir.ino], &now)
+                        .map_err(|err| err.errno())?;
+                    return Ok(());
+                }
+                if srcent.is_dir() && !dst_file.is_dir() {
+                    return Err(ENOTDIR);
+                }
+                if !srcent.is_dir() && dst_file.is_dir() {
+                    return Err(EISDIR);
+                }
+                if dst_file.is_dir()
+                    && self
+                        .db_mgr
+                        .has_children(&dst_file.ino)
+                        .map_err(|err| err.errno())?
+                {
+                    return Err(ENOTEMPTY);
+                }
+                debug!(
+                    "Removing dst file before rename: mnt: {}, ino: {}, name: {:?}",
+                    &self.mount_pt, &dst_file.ino, &dst_file.name
+                );
+                self.db_mgr
+                    .unlink(&dst_file.dentry_id, &now)
+                    .map_err(|err| err.errno())?;
+            }
+        }
+        self.db_mgr
+            .move_dentry(&srcent.dentry_id, &dst_dir.ino, newname, &now)
+            .map_err(|err| err.errno())?;
+        self.db_mgr
+            .touch_dirs(&self.mount_pt, &[parent as i64, dst_dir.ino], &now)
+            .map_err(|err| err.errno())?;
+        Ok(())
+    }
+
+    /// Creates a file for create, or opens the existing entry if another
+    /// mount won the race, truncating it in db for O_TRUNC. Only opening the
+    /// existing entry runs in a transaction.
+    fn create_entry(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: i32,
+        now: &Timespec,
+    ) -> Result<db::Ent, c_int> {
+        let created = self.db_mgr.create_file(
+            &self.mount_pt,
+            parent as i64,
+            name,
+            (mode & 0o7777) as i32,
+            &req_owner(req),
+            now,
+        );
+        match created {
+            Ok(ent) => return Ok(ent),
+            Err(PgDbFsError::Exists) => {}
+            Err(err) => {
+                error!("create failed, parent: {}, reason: {}", parent, err);
+                return Err(err.errno());
+            }
+        }
+        // Lost a race with another mount
+        self.db_mgr.begin().map_err(|err| err.errno())?;
+        match self.open_existing(parent, name, flags, now) {
+            Err(errno) => {
+                self.db_mgr.rollback();
+                Err(errno)
+            }
+            Ok(ent) => {
+                self.db_mgr.commit().map_err(|err| err.errno())?;
+                Ok(ent)
+            }
+        }
+    }
+
+    /// Looks up the entry create lost the race for and truncates it for
+    /// O_TRUNC. Runs inside the transaction opened by create_entry.
+    fn open_existing(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        flags: i32,
+        now: &Timespec,
+    ) -> Result<db::Ent, c_int> {
+        let mut ent = self
+            .db_mgr
+            .lookup(&self.mount_pt, parent as i64, name)
+            .map_err(|err| err.errno())?;
+        // Only a regular file can be opened in place of the one not created
+        if flags & O_EXCL == O_EXCL || ent.kind != db::EntKind::File {
+            return Err(EEXIST);
+        }
+        if flags & O_TRUNC == O_TRUNC {
+            self.icache.invalidate(ent.ino);
+            self.db_mgr
+                .truncate(&ent.id, 0, now)
+                .map_err(|err| err.errno())?;
+            ent.size = 0;
+        }
+        Ok(ent)
+    }
+}
+
 impl Filesystem for PgDbFs {
-    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
         debug!("init({:?}", _req);
         Ok(())
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        debug!("lookup(parent={}, name={:?})", parent, name.to_str());
-        match name.to_str() {
-            None => {
-                debug!("No value in name, parent: {}", parent);
+    /// Saves the buffers of files whose last release failed to save them
+    fn destroy(&mut self) {
+        debug!("destroy()");
+        for fb in self.fcache.fcache.values_mut() {
+            if let Err(err) = fb.save(&mut self.db_mgr) {
+                error!(
+                    "destroy failed to save, id: {}, reason: {}",
+                    fb.file_id, err
+                );
+            }
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        debug!("statfs(ino: {})", _ino);
+
+        match self.db_mgr.fs_usage(&self.mount_pt) {
+            Err(err) => {
+                error!("statfs failed: {}", err);
+                reply.error(err.errno())
+            }
+            Ok(usage) => {
+                let capacity = if self.cfg.quota_bytes > 0 {
+                    self.cfg.quota_bytes
+                } else {
+                    cmp::max(usage.db_size, usage.used_bytes)
+                };
+                let blocks = (capacity as u64).div_ceil(STATFS_BSIZE as u64);
+                let used_blocks = (usage.used_bytes as u64).div_ceil(STATFS_BSIZE as u64);
+                let bfree = blocks.saturating_sub(used_blocks);
+
+                let files = if self.cfg.quota_inodes > 0 {
+                    self.cfg.quota_inodes as u64
+                } else {
+                    u32::MAX as u64
+                };
+                let ffree = files.saturating_sub(usage.used_inodes as u64);
+
+                reply.statfs(
+                    blocks,
+                    bfree,
+                    bfree,
+                    files,
+                    ffree,
+                    STATFS_BSIZE,
+                    db::NAME_MAX as u32,
+                    STATFS_BSIZE,
+                );
+            }
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!(
+            "setxattr(ino: {}, name: {:?}, len: {}, flags: {})",
+            ino,
+            name,
+            value.len(),
+            flags
+        );
+        self.icache.invalidate(ino as i64);
+        match self
+            .db_mgr
+            .set_xattr(&self.mount_pt, ino as i64, name, value, flags)
+        {
+            Err(err) => {
+                error!("setxattr failed, ino: {}, reason: {}", ino, err);
+                reply.error(err.errno())
+            }
+            Ok(1) => reply.ok(),
+            Ok(_) => {
+                if flags & XATTR_CREATE != 0 {
+                    reply.error(EEXIST)
+                } else if flags & XATTR_REPLACE != 0 {
+                    reply.error(ENODATA)
+                } else {
+                    reply.error(ENOENT)
+                }
+            }
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr(ino: {}, name: {:?}, size: {})", ino, name, size);
+
+        match self.db_mgr.get_xattr(&self.mount_pt, ino as i64, name) {
+            Err(err) => {
+                error!("getxattr failed, ino: {}, reason: {}", ino, err);
+                reply.error(err.errno())
+            }
+            Ok(None) => reply.error(ENODATA),
+            Ok(Some(value)) => {
+                if size == 0 {
+                    reply.size(value.len() as u32)
+                } else if value.len() > size as usize {
+                    reply.error(ERANGE)
+                } else {
+                    reply.data(&value)
+                }
+            }
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr(ino: {}, size: {})", ino, size);
+
+        let list = match self.db_mgr.list_xattr(&self.mount_pt, ino as i64) {
+            Err(err) => {
+                error!("listxattr failed, ino: {}, reason: {}", ino, err);
+                reply.error(err.errno());
                 return;
             }
-            Some(n) => match self.db_mgr.lookup(&self.mount_pt, parent as i64, n) {
-                None => {
-                    debug!("No entries found for parent: {}, name: {:?}", parent, name);
-                    reply.error(ENOENT);
+            Ok(list) => list,
+        };
+        let mut names: Vec<u8> = Vec::new();
+        for n in list {
+            names.extend_from_slice(n.as_bytes());
+            names.push(0);
+        }
+        if size == 0 {
+            reply.size(names.len() as u32)
+        } else if names.len() > size as usize {
+            reply.error(ERANGE)
+        } else {
+            reply.data(&names)
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr(ino: {}, name: {:?})", ino, name);
+        self.icache.invalidate(ino as i64);
+
+        match self.db_mgr.remove_xattr(&self.mount_pt, ino as i64, name) {
+            Err(err) => {
+                error!("removexattr failed, ino: {}, reason: {}", ino, err);
+                reply.error(err.errno())
+            }
+            Ok(0) => reply.error(ENODATA),
+            Ok(_) => reply.ok(),
+        }
+    }
+
+    fn access(&mut self, _req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        debug!("access(ino: {}, mask: {}, uid: {})", ino, mask, _req.uid());
+
+        match self.db_mgr.lookup_by_ino(&self.mount_pt, ino as i64) {
+            Err(err) => reply.error(err.errno()),
+            Ok(ent) => {
+                if self.check_access(&ent, _req.uid(), _req.gid(), mask) {
+                    reply.ok()
+                } else {
+                    reply.error(EACCES)
+                }
+            }
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup(parent={}, name={:?})", parent, name);
+        let res = match self.icache.lookup(parent as i64, name) {
+            CachedLookup::Found(ent) => Ok(ent),
+            CachedLookup::Negative => Err(PgDbFsError::NotFound),
+            CachedLookup::Miss => match self.db_mgr.lookup(&self.mount_pt, parent as i64, name) {
+                Ok(ent) => {
+                    self.icache.insert(parent as i64, &ent);
+                    Ok(ent)
                 }
-                Some(ent) => {
-                    let ttl = Timespec::new(1, 0);
-                    let attr = self.make_file_entry(&ent, _req);
-                    reply.entry(&ttl, &attr, 0);
+                Err(PgDbFsError::NotFound) => {
+                    self.icache.insert_negative(parent as i64, name);
+                    Err(PgDbFsError::NotFound)
                 }
+                Err(err) => Err(err),
             },
+        };
+        match res {
+            Err(PgDbFsError::NotFound) if self.cfg.negative_ttl_ms > 0 => {
+                let attr = self.negative_entry();
+                reply.entry(&self.negative_ttl, &attr, 0);
+            }
+            Err(err) => {
+                debug!(
+                    "Lookup failed for parent: {}, name: {:?}, reason: {}",
+                    parent, name, err
+                );
+                reply.error(err.errno());
+            }
+            Ok(mut ent) => {
+                self.apply_cached_attrs(&mut ent);
+                let attr = self.make_file_entry(&ent);
+                reply.entry(&self.entry_ttl, &attr, 0);
+            }
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         debug!("getattr(ino={})", ino);
 
-        match self.db_mgr.lookup_by_ino(&self.mount_pt, ino as i64) {
-            None => {
-                debug!("No entries found for ino: {}", ino);
+        let res = match self.icache.get_attr(ino as i64) {
+            Some(ent) => Ok(ent),
+            None => self
+                .db_mgr
+                .lookup_by_ino(&self.mount_pt, ino as i64)
+                .inspect(|ent| self.icache.insert_attr(ent)),
+        };
+        match res {
+            Err(err) => {
+                debug!("getattr failed for ino: {}, reason: {}", ino, err);
+                reply.error(err.errno());
             }
-            Some(ent) => {
-                let attr = self.make_file_entry(&ent, _req);
-                let ts: Timespec = Timespec::new(ent.create_ts.timestamp(), 0);
-                //                reply.attr(&ent.create_ts, &attr);
-                reply.attr(&ts, &attr);
+            Ok(mut ent) => {
+                self.apply_cached_attrs(&mut ent);
+                let attr = self.make_file_entry(&ent);
+                reply.attr(&self.attr_ttl, &attr);
             }
         }
     }
@@ -144,12 +694,13 @@ impl Filesystem for PgDbFs {
         _uid: Option<u32>,
         _gid: Option<u32>,
         _size: Option<u64>,
-        _atime: Option<Timespec>,
-        _mtime: Option<Timespec>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
         _fh: Option<u64>,
-        _crtime: Option<Timespec>,
-        _chgtime: Option<Timespec>,
-        _bkuptime: Option<Timespec>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
@@ -158,41 +709,88 @@ impl Filesystem for PgDbFs {
             _ino, _mode, _size, _atime, _mtime
         );
 
+        self.icache.invalidate(_ino as i64);
         match self.db_mgr.lookup_by_ino(&self.mount_pt, _ino as i64) {
-            None => panic!("Failed to lookup created dir"),
-            Some(mut ent) => {
-                //let cts: Timespec = time::now_utc().to_timespec();
-                let cr_tm = match _atime {
-                    Some(val) => val,
-                    _ => Timespec::new(ent.create_ts.timestamp(), 0),
-                };
-                let up_tm = match _mtime {
-                    Some(val) => val,
-                    _ => Timespec::new(ent.update_ts.timestamp(), 0),
-                };
+            Err(err) => {
+                error!("setattr lookup failed for ino: {}, reason: {}", _ino, err);
+                reply.error(err.errno());
+            }
+            Ok(mut ent) => {
+                self.apply_cached_attrs(&mut ent);
+                let now = time::get_time();
+                let atime = _atime.map(|t| time_or_now_to_timespec(t, now));
+                let mtime = _mtime.map(|t| time_or_now_to_timespec(t, now));
+                // Refuse times that can not be stored before changing anything
+                for t in atime.iter().chain(mtime.iter()) {
+                    if let Err(err) = db::timespec_to_ns(t) {
+                        error!("setattr failed for ino: {}, reason: {}", _ino, err);
+                        reply.error(err.errno());
+                        return;
+                    }
+                }
                 let sz = match _size {
-                    Some(val) => val as i64,
+                    Some(val) => {
+                        if ent.is_dir() {
+                            reply.error(EISDIR);
+                            return;
+                        }
+                        let val = val as i64;
+                        if let Err(err) = self.truncate_file(&ent, val) {
+                            error!("setattr truncate failed for ino: {}, reason: {}", _ino, err);
+                            reply.error(err.errno());
+                            return;
+                        }
+                        val
+                    }
                     _ => ent.size,
                 };
 
+                if sz != ent.size {
+                    ent.mtime = now;
+                }
                 ent.size = sz;
-                ent.create_ts =
-                    DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_secs(cr_tm.sec as u64));
-                ent.update_ts =
-                    DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_secs(up_tm.sec as u64));
 
-                let updt_count =
-                    self.db_mgr
-                        .setattr(&self.mount_pt, _ino as i64, sz as i64, cr_tm, up_tm);
+                let mode = _mode.map(|m| (m & 0o7777) as i32);
+                let uid = _uid.map(|u| u as i64);
+                let gid = _gid.map(|g| g as i64);
+                ent.mode = mode.unwrap_or(ent.mode);
+                ent.uid = uid.unwrap_or(ent.uid);
+                ent.gid = gid.unwrap_or(ent.gid);
 
-                debug!(
-                    "setattr(DB update count: {}, for: {}, {})",
-                    updt_count, self.mount_pt, _ino
-                );
+                ent.atime = atime.unwrap_or(ent.atime);
+                ent.mtime = mtime.unwrap_or(ent.mtime);
+                ent.ctime = now;
+                if let Some(mtime) = mtime {
+                    // Keep a cached buffer from saving its older write time
+                    if let Some(fb) = self.fcache.get(&self.mount_pt, &ent.ino) {
+                        fb.mtime = mtime;
+                    }
+                }
 
-                let attr = self.make_file_entry(&ent, _req);
+                let changes = db::AttrChanges {
+                    mode: mode,
+                    uid: uid,
+                    gid: gid,
+                    atime: atime,
+                    mtime: mtime,
+                    ctime: now,
+                };
+                match self.db_mgr.setattr(&self.mount_pt, _ino as i64, &changes) {
+                    Err(err) => {
+                        error!("setattr failed for ino: {}, reason: {}", _ino, err);
+                        reply.error(err.errno());
+                    }
+                    Ok(updt_count) => {
+                        debug!(
+                            "setattr(DB update count: {}, for: {}, {})",
+                            updt_count, self.mount_pt, _ino
+                        );
+
+                        let attr = self.make_file_entry(&ent);
 
-                reply.attr(&attr.ctime, &attr);
+                        reply.attr(&self.attr_ttl, &attr);
+                    }
+                }
             }
         }
     }
@@ -203,32 +801,29 @@ impl Filesystem for PgDbFs {
         parent: u64,
         name: &OsStr,
         mode: u32,
+        _umask: u32,
         rdev: u32,
         reply: ReplyEntry,
     ) {
         debug!(
             "mknod (parent: {}, mode: {}, rdev: {}, name: {:?}",
-            parent,
-            mode,
-            rdev,
-            name.to_str()
+            parent, mode, rdev, name
         );
 
-        self.db_mgr.mkfile(
+        self.invalidate_entry(parent, name);
+        match self.db_mgr.create_file(
             &self.mount_pt,
             parent as i64,
-            &name.to_str().unwrap(),
-            &self.cfg.db_segment_len,
-        );
-        match self
-            .db_mgr
-            .lookup(&self.mount_pt, parent as i64, &name.to_str().unwrap())
-        {
-            None => panic!("Failed to lookup created dir"),
-            Some(ent) => {
-                let attr = self.make_file_entry(&ent, _req);
-                let ts: Timespec = Timespec::new(ent.create_ts.timestamp(), 0);
-                reply.entry(&ts, &attr, 0);
+            name,
+            (mode & 0o7777) as i32,
+            &req_owner(_req),
+            &time::get_time(),
+        ) {
+            Err(err) => reply.error(err.errno()),
+            Ok(ent) => {
+                self.icache.insert(parent as i64, &ent);
+                let attr = self.make_file_entry(&ent);
+                reply.entry(&self.entry_ttl, &attr, 0);
             }
         }
     }
@@ -239,38 +834,101 @@ impl Filesystem for PgDbFs {
         _parent: u64,
         _name: &OsStr,
         _mode: u32,
+        _umask: u32,
         reply: ReplyEntry,
     ) {
-        debug!("mkdir(parent: {}, name: {:?}", _parent, _name.to_str());
-        self.db_mgr
-            .mkdir(&self.mount_pt, _parent as i64, &_name.to_str().unwrap());
-        match self
-            .db_mgr
-            .lookup(&self.mount_pt, _parent as i64, &_name.to_str().unwrap())
-        {
-            None => panic!("Failed to lookup created dir"),
-            Some(ent) => {
-                let attr = self.make_file_entry(&ent, _req);
-                let ts: Timespec = Timespec::new(ent.create_ts.timestamp(), 0);
-                reply.entry(&ts, &attr, 0);
+        debug!("mkdir(parent: {}, name: {:?}", _parent, _name);
+        self.invalidate_entry(_parent, _name);
+        match self.db_mgr.mkdir(
+            &self.mount_pt,
+            _parent as i64,
+            _name,
+            (_mode & 0o7777) as i32,
+            &req_owner(_req),
+            &time::get_time(),
+        ) {
+            Err(err) => {
+                debug!("mkdir failed, parent: {}, reason: {}", _parent, err);
+                reply.error(err.errno())
+            }
+            Ok(ent) => {
+                self.icache.insert(_parent as i64, &ent);
+                let attr = self.make_file_entry(&ent);
+                reply.entry(&self.entry_ttl, &attr, 0);
+            }
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        debug!(
+            "symlink(parent: {}, name: {:?}, link: {:?})",
+            parent, name, link
+        );
+        self.invalidate_entry(parent, name);
+        match self.db_mgr.create_symlink(
+            &self.mount_pt,
+            parent as i64,
+            name,
+            link.as_os_str(),
+            &req_owner(_req),
+            &time::get_time(),
+        ) {
+            Err(err) => reply.error(err.errno()),
+            Ok(ent) => {
+                self.icache.insert(parent as i64, &ent);
+                let attr = self.make_file_entry(&ent);
+                reply.entry(&self.entry_ttl, &attr, 0);
             }
         }
     }
 
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        debug!("readlink(ino: {})", ino);
+
+        match self.db_mgr.lookup_by_ino(&self.mount_pt, ino as i64) {
+            Err(err) => reply.error(err.errno()),
+            Ok(ent) => match ent.link_target {
+                Some(target) => reply.data(target.as_bytes()),
+                None => reply.error(EINVAL),
+            },
+        }
+    }
+
     fn unlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        match self
-            .db_mgr
-            .lookup(&self.mount_pt, _parent as i64, &_name.to_str().unwrap())
-        {
-            None => reply.error(ENOENT),
-            Some(ent) => {
-                if ent.is_dir {
-                    reply.error(EISDIR)
+        self.invalidate_entry(_parent, _name);
+        if let Err(err) = self.db_mgr.begin() {
+            reply.error(err.errno());
+            return;
+        }
+        let res = match self.db_mgr.lookup(&self.mount_pt, _parent as i64, _name) {
+            Err(err) => Err(err.errno()),
+            Ok(ent) => {
+                self.icache.invalidate(ent.ino);
+                if ent.is_dir() {
+                    Err(EISDIR)
                 } else {
-                    self.db_mgr.delete_entity(&ent.id);
-                    reply.ok();
+                    self.db_mgr
+                        .unlink(&ent.dentry_id, &time::get_time())
+                        .map_err(|err| err.errno())
                 }
             }
+        };
+        match res {
+            Err(errno) => {
+                self.db_mgr.rollback();
+                reply.error(errno)
+            }
+            Ok(_) => match self.db_mgr.commit() {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.errno()),
+            },
         }
     }
 
@@ -281,60 +939,60 @@ impl Filesystem for PgDbFs {
         _name: &OsStr,
         _newparent: u64,
         _newname: &OsStr,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
-        let dst_dir = self.db_mgr.lookup_by_ino(&self.mount_pt, _newparent as i64);
-        match dst_dir {
-            None => {
-                error!(
-                    "Dst dir does not exist: mnt: {} ino: {}",
-                    self.mount_pt, _newparent
-                );
-                reply.error(ENOENT);
-                return;
-            }
-            Some(ent) => {
-                if !ent.is_dir {
-                    error!(
-                        "Dst is not a directory, mnt: {}, ino: {}",
-                        self.mount_pt, _newparent
-                    );
-                    reply.error(ENOTDIR);
+        debug!(
+            "rename(parent: {}, name: {:?}, newparent: {}, newname: {:?}, flags: {})",
+            _parent, _name, _newparent, _newname, flags
+        );
+        match self.rename_atomically(_parent, _name, _newparent, _newname, flags) {
+            Err(errno) => reply.error(errno),
+            Ok(()) => reply.ok(),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        debug!(
+            "link(ino: {}, newparent: {}, newname: {:?})",
+            ino, newparent, newname
+        );
+        self.icache.invalidate(ino as i64);
+        self.invalidate_entry(newparent, newname);
+        match self.db_mgr.lookup_by_ino(&self.mount_pt, ino as i64) {
+            Err(err) => reply.error(err.errno()),
+            Ok(ent) => {
+                if ent.is_dir() {
+                    reply.error(EPERM);
                     return;
                 }
-                let src_file =
-                    self.db_mgr
-                        .lookup(&self.mount_pt, _parent as i64, &_name.to_str().unwrap());
-                match src_file {
-                    None => {
-                        error!(
-                            "Src file does not exist, mnt: {}, ino: {}, file: {}",
-                            self.mount_pt,
-                            _parent,
-                            _name.to_str().unwrap()
-                        );
-                        reply.error(ENOENT);
-                        return;
-                    }
-                    Some(srcent) => {
-                        match self.db_mgr.lookup(
-                            &self.mount_pt,
-                            _newparent as i64,
-                            &_newname.to_str().unwrap(),
-                        ) {
-                            Some(dst_file) => {
-                                debug!(
-                                    "Removing dst file before rename: mnt: {}, ino: {}, name: {}",
-                                    &self.mount_pt, &dst_file.ino, &dst_file.name
-                                );
-                                self.db_mgr.delete_entity(&dst_file.id);
-                            }
-                            None => {}
-                        }
-                        self.db_mgr.update_parent(&srcent.id, &ent.ino);
-                        self.db_mgr
-                            .update_name(&srcent.id, _newname.to_str().unwrap());
-                        reply.ok();
+                if let Err(err) = self.db_mgr.link(
+                    &self.mount_pt,
+                    ent.ino,
+                    newparent as i64,
+                    newname,
+                    &time::get_time(),
+                ) {
+                    reply.error(err.errno());
+                    return;
+                }
+                match self
+                    .db_mgr
+                    .lookup(&self.mount_pt, newparent as i64, newname)
+                {
+                    Err(err) => reply.error(err.errno()),
+                    Ok(mut ent) => {
+                        self.icache.insert(newparent as i64, &ent);
+                        self.apply_cached_attrs(&mut ent);
+                        let attr = self.make_file_entry(&ent);
+                        reply.entry(&self.entry_ttl, &attr, 0);
                     }
                 }
             }
@@ -342,56 +1000,110 @@ impl Filesystem for PgDbFs {
     }
 
     fn rmdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        match self
-            .db_mgr
-            .lookup(&self.mount_pt, _parent as i64, &_name.to_str().unwrap())
-        {
-            None => reply.error(ENOENT),
-            Some(ent) => {
-                if !ent.is_dir {
-                    reply.error(ENOTDIR)
-                } else if self.db_mgr.has_children(&ent.ino) {
-                    reply.error(ENOTEMPTY)
+        self.invalidate_entry(_parent, _name);
+        if let Err(err) = self.db_mgr.begin() {
+            reply.error(err.errno());
+            return;
+        }
+        let res = match self.db_mgr.lookup(&self.mount_pt, _parent as i64, _name) {
+            Err(err) => Err(err.errno()),
+            Ok(ent) => {
+                self.icache.invalidate(ent.ino);
+                if !ent.is_dir() {
+                    Err(ENOTDIR)
                 } else {
-                    self.db_mgr.delete_entity(&ent.id);
-                    reply.ok();
+                    match self.db_mgr.has_children(&ent.ino) {
+                        Err(err) => Err(err.errno()),
+                        Ok(true) => Err(ENOTEMPTY),
+                        Ok(false) => self
+                            .db_mgr
+                            .unlink(&ent.dentry_id, &time::get_time())
+                            .map_err(|err| err.errno()),
+                    }
                 }
             }
+        };
+        match res {
+            Err(errno) => {
+                self.db_mgr.rollback();
+                reply.error(errno)
+            }
+            Ok(_) => match self.db_mgr.commit() {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.errno()),
+            },
         }
     }
 
-    fn open(&mut self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+    fn open(&mut self, _req: &Request, _ino: u64, _flags: i32, reply: ReplyOpen) {
         debug!(
             "open(ino: {}, flags: {}, req: {})",
             _ino,
             _flags,
             _req.unique()
         );
-        print_flags(&"open", _flags as i32);
+        print_flags(&"open", _flags);
         match self.db_mgr.lookup_by_ino(&self.mount_pt, _ino as i64) {
-            None => {
-                debug!("No entries found for ino: {}", _ino);
-                reply.error(ENOENT);
+            Err(err) => {
+                debug!("open lookup failed for ino: {}, reason: {}", _ino, err);
+                reply.error(err.errno());
             }
-            Some(ent) => {
-                let rw: bool = _flags as i32 & O_ACCMODE == O_RDWR;
-                let wo: bool = _flags as i32 & O_ACCMODE == O_WRONLY;
-                let ap: bool = _flags as i32 & O_APPEND == O_APPEND;
+            Ok(mut ent) => {
+                let rw: bool = _flags & O_ACCMODE == O_RDWR;
+                let wo: bool = _flags & O_ACCMODE == O_WRONLY;
+                let tr: bool = _flags & O_TRUNC == O_TRUNC;
 
-                if (rw || wo) && !ap {
-                    debug!(
-                        "open({}) - File opened for write, clearning data if exists",
-                        ent.id,
-                    );
-                    self.db_mgr.clear_file_data(&ent.id);
+                if (rw || wo) && tr {
+                    debug!("open({}) - File opened with O_TRUNC, truncating", ent.id);
+                    if let Err(err) = self.truncate_file(&ent, 0) {
+                        error!("open truncate failed for ino: {}, reason: {}", _ino, err);
+                        reply.error(err.errno());
+                        return;
+                    }
+                    ent.size = 0;
                 }
 
-                self.fcache
-                    .init(&self.mount_pt, ent.ino, ent.id, _flags, ent.segment_len);
-                reply.opened(_ino, _flags)
+                let fh = self.fcache.open(&self.mount_pt, &ent, _flags as u32);
+                reply.opened(fh, _flags as u32)
             }
         }
-        //        reply.opened(0, flags);
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        debug!(
+            "create(parent: {}, name: {:?}, mode: {}, flags: {})",
+            parent, name, mode, flags
+        );
+        print_flags(&"create", flags);
+
+        self.invalidate_entry(parent, name);
+        let now = time::get_time();
+        let mut ent = match self.create_entry(_req, parent, name, mode, flags, &now) {
+            Ok(ent) => ent,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        if flags & O_TRUNC == O_TRUNC {
+            // An existing file was truncated in db, drop what is still cached
+            if let Some(fb) = self.fcache.get(&self.mount_pt, &ent.ino) {
+                fb.truncate(0, now, &mut self.scache);
+            }
+        }
+        let fh = self.fcache.open(&self.mount_pt, &ent, flags as u32);
+        self.apply_cached_attrs(&mut ent);
+        let attr = self.make_file_entry(&ent);
+        reply.created(&self.entry_ttl, &attr, 0, fh, flags as u32);
     }
 
     fn read(
@@ -401,6 +1113,8 @@ impl Filesystem for PgDbFs {
         _fh: u64,
         _offset: i64,
         _size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
         debug!(
@@ -412,19 +1126,53 @@ impl Filesystem for PgDbFs {
             _req.unique()
         );
 
-        match self
-            .fcache
-            .get_cached(&self.mount_pt, _ino as i64, 0, &mut self.db_mgr)
-        {
+        let (ino, first_read, sequential) = match self.fcache.get_handle(_fh) {
+            None => {
+                error!("Unknown file handle, ino: {}, fh: {}", _ino, _fh);
+                reply.error(EBADF);
+                return;
+            }
+            Some(handle) => {
+                // Reads continuing where the last one ended get read-ahead
+                let sequential = handle.pos == _offset;
+                handle.pos = _offset + _size as i64;
+                let first_read = !handle.accessed;
+                handle.accessed = true;
+                (handle.ino, first_read, sequential)
+            }
+        };
+
+        match self.fcache.get(&self.mount_pt, &ino) {
             None => {
                 error!("File not cached, ino: {}", _ino);
                 reply.error(ENOENT)
             }
             Some(fb) => {
                 debug!("Cache found, ino: {}", fb.file_id);
-                match fb.read(_offset, _size as i32, &mut self.db_mgr) {
-                    Some(data) => reply.data(data.as_slice()),
-                    None => reply.error(ENOENT),
+                if first_read {
+                    self.icache.invalidate(ino);
+                    // A missed access time update does not fail the read
+                    if let Err(err) = self.db_mgr.touch_atime(&fb.file_id, &time::get_time()) {
+                        error!("touch_atime failed, ino: {}, reason: {}", _ino, err);
+                    }
+                }
+                let readahead = if sequential {
+                    self.cfg.readahead_segments
+                } else {
+                    0
+                };
+                match fb.read(
+                    _offset,
+                    _size as i32,
+                    readahead,
+                    &mut self.scache,
+                    &mut self.db_mgr,
+                ) {
+                    Ok(data) => reply.data(data.as_slice()),
+                    Err(err) => {
+                        error!("read failed, ino: {}, reason: {}", _ino, err);
+                        reply.error(err.errno())
+                    }
                 }
             }
         }
@@ -437,7 +1185,9 @@ impl Filesystem for PgDbFs {
         _fh: u64,
         _offset: i64,
         _data: &[u8],
-        _flags: u32,
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
         debug!(
@@ -448,45 +1198,151 @@ impl Filesystem for PgDbFs {
             _flags
         );
 
-        print_flags(&"write", _flags as i32);
+        print_flags(&"write", _flags);
 
-        match self
-            .fcache
-            .get_cached(&self.mount_pt, _ino as i64, _flags, &mut self.db_mgr)
-        {
+        let (ino, flags) = match self.fcache.get_handle(_fh) {
+            None => {
+                error!("Unknown file handle, ino: {}, fh: {}", _ino, _fh);
+                reply.error(EBADF);
+                return;
+            }
+            Some(handle) => {
+                handle.dirty = true;
+                (handle.ino, handle.flags)
+            }
+        };
+        self.icache.invalidate(ino);
+
+        let offset = match self.fcache.get(&self.mount_pt, &ino) {
             None => {
                 error!("File not cached, ino: {}", _ino);
-                reply.error(ENOENT)
+                reply.error(ENOENT);
+                return;
             }
             Some(fb) => {
                 debug!("Cache found, ino: {}", fb.file_id);
-                fb.add(_offset, _data, &mut self.db_mgr);
-                return reply.written(_data.len() as u32);
+                // Appends always go to the current end of the shared buffer
+                let offset = if flags as i32 & O_APPEND == O_APPEND {
+                    fb.size
+                } else {
+                    _offset
+                };
+                if let Err(err) = fb.add(
+                    offset,
+                    _data,
+                    time::get_time(),
+                    &mut self.scache,
+                    &mut self.db_mgr,
+                ) {
+                    error!("write failed, ino: {}, reason: {}", _ino, err);
+                    reply.error(err.errno());
+                    return;
+                }
+                offset
             }
+        };
+        if let Some(handle) = self.fcache.get_handle(_fh) {
+            handle.pos = offset + _data.len() as i64;
         }
+        reply.written(_data.len() as u32);
     }
 
     fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
         debug!("flush(ino: {} fh: {}, uid: {})", _ino, _fh, _req.unique());
 
-        match self.db_mgr.lookup_by_ino(&self.mount_pt, _ino as i64) {
+        let ino = match self.fcache.get_handle(_fh) {
             None => {
-                debug!("No entries found for ino: {}", _ino);
-                reply.error(ENOENT);
+                reply.error(EBADF);
+                return;
             }
-            Some(ent) => match self.fcache.remove(&self.mount_pt, &ent.ino) {
-                Some(mut fb) => {
-                    let flags_t: i32 = fb.flags as i32;
-                    if flags_t & O_ACCMODE == O_RDWR || flags_t & O_ACCMODE == O_WRONLY {
-                        fb.save(&mut self.db_mgr);
-                    }
+            Some(handle) => {
+                if !handle.dirty {
+                    reply.ok();
+                    return;
+                }
+                handle.dirty = false;
+                handle.ino
+            }
+        };
+        // Saves what any handle on the file wrote, the buffer stays cached
+        // for the other handles until the last one is released
+        if let Some(fb) = self.fcache.get(&self.mount_pt, &ino) {
+            if let Err(err) = fb.save(&mut self.db_mgr) {
+                error!("flush failed, ino: {}, reason: {}", _ino, err);
+                if let Some(handle) = self.fcache.get_handle(_fh) {
+                    handle.dirty = true;
+                }
+                reply.error(err.errno());
+                return;
+            }
+            // Saving changed the size and times stored for the inode
+            self.icache.invalidate(ino);
+        }
+        reply.ok()
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!("release(ino: {} fh: {})", _ino, _fh);
+
+        if let Some(ino) = self.fcache.release(&self.mount_pt, _fh) {
+            if let Some(fb) = self.fcache.get(&self.mount_pt, &ino) {
+                // Only open files keep segments cached
+                self.scache.invalidate_file(fb.file_id);
+                debug!("{}", self.scache);
+                if let Err(err) = fb.save(&mut self.db_mgr) {
+                    // The buffer stays cached with its dirty segments, saved
+                    // again when the file is next released or on unmount
+                    error!("release failed to save, ino: {}, reason: {}", _ino, err);
+                    reply.error(err.errno());
+                    return;
+                }
+                self.icache.invalidate(ino);
+            }
+            self.fcache.remove(&self.mount_pt, &ino);
+        }
+        reply.ok()
+    }
+
+    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        debug!("fsync(ino: {}, fh: {}, datasync: {})", _ino, _fh, _datasync);
+
+        match self.fcache.get(&self.mount_pt, &(_ino as i64)) {
+            Some(fb) => match fb.sync(&mut self.db_mgr) {
+                Ok(()) => {
+                    self.icache.invalidate(_ino as i64);
                     reply.ok()
                 }
-                None => reply.ok(),
+                Err(err) => {
+                    error!("fsync failed, ino: {}, reason: {}", _ino, err);
+                    reply.error(err.errno())
+                }
             },
+            None => reply.ok(),
         }
     }
 
+    fn fsyncdir(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        // Directory changes are committed as they are made
+        debug!("fsyncdir(ino: {}, fh: {})", _ino, _fh);
+        reply.ok();
+    }
+
     //
     fn readdir(
         &mut self,
@@ -504,32 +1360,64 @@ impl Filesystem for PgDbFs {
             self.mount_pt,
             _req.uid()
         );
-        let mut off = offset;
-        let entries: Vec<db::Ent> = self
-            .db_mgr
-            .ls(self.mount_pt.to_string(), ino as i64, offset);
-        debug!(
-            "readdir(ino={}, fh={}, offset={}, mnt_pt: {}, num_files: {})",
-            ino,
-            fh,
-            offset,
-            self.mount_pt,
-            entries.len()
-        );
-
-        for e in entries {
-            off += 1;
-            let fill = reply.add(
-                e.ino as u64,
-                off,
-                if e.is_dir {
-                    FileType::Directory
-                } else {
-                    FileType::RegularFile
-                },
-                &Path::new(&e.name),
+        // "." and ".." use cookies 1 and 2, entries use their dentry id
+        // shifted past those, so a cookie always resumes after the last entry
+        // returned even when entries were added or removed in between.
+        if offset < 1 && reply.add(ino, 1, FileType::Directory, ".") {
+            reply.ok();
+            return;
+        }
+        if offset < 2 {
+            let parent = match self.db_mgr.parent_ino(&self.mount_pt, ino as i64) {
+                Ok(parent) => parent as u64,
+                Err(err) => {
+                    error!("readdir failed, ino: {}, reason: {}", ino, err);
+                    reply.error(err.errno());
+                    return;
+                }
+            };
+            if reply.add(parent, 2, FileType::Directory, "..") {
+                reply.ok();
+                return;
+            }
+        }
+        let mut after_id = cmp::max(offset - 2, 0);
+        loop {
+            let entries: Vec<db::Ent> =
+                match self
+                    .db_mgr
+                    .ls(&self.mount_pt, ino as i64, after_id, READDIR_BATCH)
+                {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        error!("readdir failed, ino: {}, reason: {}", ino, err);
+                        reply.error(err.errno());
+                        return;
+                    }
+                };
+            debug!(
+                "readdir(ino={}, fh={}, after_id={}, mnt_pt: {}, num_files: {})",
+                ino,
+                fh,
+                after_id,
+                self.mount_pt,
+                entries.len()
             );
-            if fill {
+            let last_batch = (entries.len() as i64) < READDIR_BATCH;
+            for e in entries {
+                self.icache.insert(ino as i64, &e);
+                if reply.add(
+                    e.ino as u64,
+                    e.dentry_id + 2,
+                    self.file_type(e.kind),
+                    &e.name,
+                ) {
+                    reply.ok();
+                    return;
+                }
+                after_id = e.dentry_id;
+            }
+            if last_batch {
                 break;
             }
         }
@@ -537,6 +1425,46 @@ impl Filesystem for PgDbFs {
     }
 }
 
+/// Owner of an inode created for the caller of a request
+fn req_owner(req: &Request) -> db::Owner {
+    db::Owner {
+        uid: req.uid() as i64,
+        gid: req.gid() as i64,
+    }
+}
+
+/// Converts a stored timestamp to the SystemTime replied to the kernel
+fn timespec_to_system_time(ts: &Timespec) -> SystemTime {
+    let since_epoch = Duration::new(ts.sec.unsigned_abs(), 0);
+    let t = if ts.sec < 0 {
+        UNIX_EPOCH - since_epoch
+    } else {
+        UNIX_EPOCH + since_epoch
+    };
+    t + Duration::from_nanos(ts.nsec as u64)
+}
+
+/// Converts a time set by the kernel to a Timespec, `Now` becomes now
+fn time_or_now_to_timespec(t: TimeOrNow, now: Timespec) -> Timespec {
+    let t = match t {
+        TimeOrNow::Now => return now,
+        TimeOrNow::SpecificTime(t) => t,
+    };
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => Timespec::new(d.as_secs() as i64, d.subsec_nanos() as i32),
+        Err(err) => {
+            // Before the epoch, keep nsec positive like Timespec does
+            let d = err.duration();
+            let nsec = d.subsec_nanos() as i32;
+            if nsec == 0 {
+                Timespec::new(-(d.as_secs() as i64), 0)
+            } else {
+                Timespec::new(-(d.as_secs() as i64) - 1, 1_000_000_000 - nsec)
+            }
+        }
+    }
+}
+
 pub fn print_flags(tag: &str, flags: i32) {
     let ro: bool = flags as i32 & O_ACCMODE == O_RDONLY;
     let rw: bool = flags as i32 & O_ACCMODE == O_RDWR;
@@ -549,6 +1477,25 @@ pub fn print_flags(tag: &str, flags: i32) {
     );
 }
 
+/// Recomputes file sizes on the mount point from the stored segment data,
+/// shrink also cuts sizes that end past the stored data
+pub fn recompute_sizes(path: String, cfg_path: String, shrink: bool) {
+    let cfg: PgDbFsConfig = confy::load(&cfg_path).unwrap();
+
+    info!("Recomputing file sizes for: {}, config: {}", path, cfg_path);
+
+    let mut db_mgr = PgDbMgr::new(cfg);
+    if let Err(err) = db_mgr.init() {
+        error!("Failed to connect to db: {}", err);
+        return;
+    }
+
+    match db_mgr.recompute_sizes(&path, shrink) {
+        Ok(updt_count) => info!("Corrected size of {} files on: {}", updt_count, path),
+        Err(err) => error!("Failed to recompute sizes on: {}, reason: {}", path, err),
+    }
+}
+
 pub fn mount(path: String, cfg_path: String) {
     let cfg: PgDbFsConfig = confy::load(&cfg_path).unwrap();
     let cfg_clone = cfg.clone();
@@ -557,16 +1504,22 @@ pub fn mount(path: String, cfg_path: String) {
 
     let mountpt = Path::new(&path);
     let mut db_mgr = PgDbMgr::new(cfg);
-    db_mgr.init();
+    if let Err(err) = db_mgr.init() {
+        error!("Failed to connect to db: {}", err);
+        return;
+    }
 
-    let pgdbfs = PgDbFs {
-        mount_pt: path.to_string(),
-        db_mgr: db_mgr,
-        fcache: fcache::FCache::new(),
-        cfg: cfg_clone,
-    };
+    let pgdbfs = PgDbFs::new(path.to_string(), cfg_clone.clone(), db_mgr);
+
+    let mut options = vec![MountOption::FSName("pgdbfs".to_string())];
+    if cfg_clone.default_permissions {
+        options.push(MountOption::DefaultPermissions);
+    }
+    if cfg_clone.allow_other {
+        options.push(MountOption::AllowOther);
+    }
 
-    let result = fuse::mount(pgdbfs, &mountpt, &[]);
+    let result = fuser::mount2(pgdbfs, &mountpt, &options);
     match result {
         Ok(_r) => {
             debug!("Mounting pgdbfs on path: {}", path);
@@ -0,0 +1,186 @@
+extern crate time;
+
+use self::time::{Duration, Timespec};
+
+use db::Ent;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+
+/// Number of cached inodes or entries above which the cache is purged
+const PURGE_THRESHOLD: usize = 65536;
+
+/// Number of inodes and entries at most left after a purge
+const PURGE_KEEP: usize = PURGE_THRESHOLD / 2;
+
+#[derive(Debug)]
+struct CachedAttr {
+    ent: Ent,
+    expires: Timespec,
+}
+
+/// Inode a cached directory entry refers to, None if the name is known not
+/// to exist
+#[derive(Debug)]
+struct CachedEntry {
+    ino: Option<i64>,
+    expires: Timespec,
+}
+
+/// Result of looking up a directory entry in the cache
+#[derive(Debug)]
+pub enum CachedLookup {
+    Found(Ent),
+    /// The name is known not to exist
+    Negative,
+    Miss,
+}
+
+/// Attributes of recently used inodes and the directory entries leading to
+/// them, so lookups and getattrs do not query the db. Entries expire after
+/// their ttl and are dropped when changed locally.
+#[derive(Debug)]
+pub struct ICache {
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    negative_ttl: Duration,
+    attrs: HashMap<i64, CachedAttr>,
+    entries: HashMap<(i64, OsString), CachedEntry>,
+}
+
+impl fmt::Display for ICache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Cached inodes: {}, cached entries: {}",
+            self.attrs.len(),
+            self.entries.len()
+        )
+    }
+}
+
+impl ICache {
+    pub fn new(entry_ttl: Duration, attr_ttl: Duration, negative_ttl: Duration) -> ICache {
+        ICache {
+            entry_ttl: entry_ttl,
+            attr_ttl: attr_ttl,
+            negative_ttl: negative_ttl,
+            attrs: HashMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Caches the attributes of an entry and its name under parent
+    pub fn insert(&mut self, parent: i64, ent: &Ent) {
+        if self.entries.len() >= PURGE_THRESHOLD {
+            self.purge();
+        }
+        let expires = time::get_time() + self.entry_ttl;
+        self.entries.insert(
+            (parent, ent.name.clone()),
+            CachedEntry {
+                ino: Some(ent.ino),
+                expires: expires,
+            },
+        );
+        self.insert_attr(ent);
+    }
+
+    /// Records that parent has no entry named name
+    pub fn insert_negative(&mut self, parent: i64, name: &OsStr) {
+        if self.negative_ttl <= Duration::zero() {
+            return;
+        }
+        if self.entries.len() >= PURGE_THRESHOLD {
+            self.purge();
+        }
+        let expires = time::get_time() + self.negative_ttl;
+        self.entries.insert(
+            (parent, name.to_os_string()),
+            CachedEntry {
+                ino: None,
+                expires: expires,
+            },
+        );
+    }
+
+    /// Caches the attributes of an inode
+    pub fn insert_attr(&mut self, ent: &Ent) {
+        if self.attrs.len() >= PURGE_THRESHOLD {
+            self.purge();
+        }
+        let expires = time::get_time() + self.attr_ttl;
+        self.attrs.insert(
+            ent.ino,
+            CachedAttr {
+                ent: ent.clone(),
+                expires: expires,
+            },
+        );
+    }
+
+    /// Looks up the cached entry named name under parent
+    pub fn lookup(&mut self, parent: i64, name: &OsStr) -> CachedLookup {
+        let key = (parent, name.to_os_string());
+        let ino = match self.entries.get(&key) {
+            Some(entry) if entry.expires > time::get_time() => entry.ino,
+            Some(_) => {
+                self.entries.remove(&key);
+                return CachedLookup::Miss;
+            }
+            None => return CachedLookup::Miss,
+        };
+        match ino {
+            None => CachedLookup::Negative,
+            Some(ino) => match self.get_attr(ino) {
+                Some(ent) => CachedLookup::Found(ent),
+                None => CachedLookup::Miss,
+            },
+        }
+    }
+
+    /// Returns the cached attributes of an inode
+    pub fn get_attr(&mut self, ino: i64) -> Option<Ent> {
+        match self.attrs.get(&ino) {
+            Some(attr) if attr.expires > time::get_time() => return Some(attr.ent.clone()),
+            Some(_) => {}
+            None => return None,
+        }
+        self.attrs.remove(&ino);
+        None
+    }
+
+    /// Drops the cached attributes of an inode
+    pub fn invalidate(&mut self, ino: i64) {
+        self.attrs.remove(&ino);
+    }
+
+    /// Drops the cached entry named name under parent
+    pub fn invalidate_entry(&mut self, parent: i64, name: &OsStr) {
+        self.entries.remove(&(parent, name.to_os_string()));
+    }
+
+    /// Drops expired inodes and entries, then those closest to expiring
+    /// until at most PURGE_KEEP of each are left
+    fn purge(&mut self) {
+        let now = time::get_time();
+        self.attrs.retain(|_, attr| attr.expires > now);
+        self.entries.retain(|_, entry| entry.expires > now);
+        if self.attrs.len() > PURGE_KEEP {
+            let cutoff = expiry_cutoff(self.attrs.values().map(|a| a.expires).collect());
+            self.attrs.retain(|_, attr| attr.expires > cutoff);
+        }
+        if self.entries.len() > PURGE_KEEP {
+            let cutoff = expiry_cutoff(self.entries.values().map(|e| e.expires).collect());
+            self.entries.retain(|_, entry| entry.expires > cutoff);
+        }
+        debug!("icache purged, {}", self);
+    }
+}
+
+/// Expiry time at or before which items are dropped to keep at most
+/// PURGE_KEEP of them
+fn expiry_cutoff(mut expires: Vec<Timespec>) -> Timespec {
+    let idx = expires.len() - PURGE_KEEP - 1;
+    *expires.select_nth_unstable(idx).1
+}
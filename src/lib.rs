@@ -6,15 +6,37 @@ extern crate serde;
 pub mod db;
 pub mod fcache;
 pub mod fsys;
+pub mod icache;
 
 extern crate lazy_static;
 
 #[cfg(test)]
 mod tests {
-    use db;
-    use db::Ent;
+    extern crate libc;
+    extern crate time;
+
+    use self::libc::{EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY};
+    use self::libc::{RENAME_EXCHANGE, RENAME_NOREPLACE, XATTR_CREATE, XATTR_REPLACE};
+    use self::time::{Duration, Timespec};
+    use db::{ns_to_timespec, timespec_to_ns};
+    use db::{Ent, EntKind, Owner, PgDbFsError, PgDbMgr};
+    use fcache::{FBuffer, FCache, SegmentCache};
     use fsys;
-    use std::fs;
+    use icache::{CachedLookup, ICache};
+    use std::env;
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration as StdDuration;
+
+    static NEXT_MNT_PT: AtomicUsize = AtomicUsize::new(0);
+
+    static OWNER: Owner = Owner {
+        uid: 1000,
+        gid: 1000,
+    };
 
     // #[test]
     // fn test_db_conn() {
@@ -24,49 +46,747 @@ mod tests {
     //     }
     // }
 
+    /// Needs a mount point and the config file of a running database
     #[test]
+    #[ignore]
     fn test_fs() {
         let path = String::from("/tmp/pgdbfs");
-        fsys::mount(path);
+        fsys::mount(path, String::from("pgdbfs"));
+    }
+
+    /// File inode named name, as if just looked up
+    fn test_ent(ino: i64, name: &str) -> Ent {
+        let now = time::get_time();
+        Ent {
+            id: ino,
+            dentry_id: ino,
+            name: OsString::from(name),
+            kind: EntKind::File,
+            ino: ino,
+            size: 0,
+            segment_len: 4,
+            mode: 0o644,
+            uid: 1000,
+            gid: 1000,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            nlink: 1,
+            link_target: None,
+        }
+    }
+
+    /// Config for the database on PGDBFS_TEST_DB_HOST, which must have the
+    /// schema from pgdbfs.sql loaded. Tests using it are ignored unless run
+    /// with --ignored, and then fail when the variable is not set.
+    fn test_cfg() -> fsys::PgDbFsConfig {
+        let host = env::var("PGDBFS_TEST_DB_HOST")
+            .expect("PGDBFS_TEST_DB_HOST must name the host of the test database");
+        fsys::PgDbFsConfig {
+            db_host: host,
+            db_segment_len: 4,
+            ..Default::default()
+        }
+    }
+
+    fn test_db() -> PgDbMgr {
+        let mut db_mgr = PgDbMgr::new(test_cfg());
+        db_mgr.init().unwrap();
+        db_mgr
+    }
+
+    /// File system on mnt_pt backed by the test database
+    fn test_pgdbfs(mnt_pt: &str) -> fsys::PgDbFs {
+        let cfg = test_cfg();
+        let mut db_mgr = PgDbMgr::new(cfg.clone());
+        db_mgr.init().unwrap();
+        fsys::PgDbFs::new(mnt_pt.to_string(), cfg, db_mgr)
+    }
+
+    /// Mount point of its own for every test, so runs never share entries
+    fn test_mnt_pt() -> String {
+        format!(
+            "/tmp/pgdbfs-test-{}-{}-{}",
+            process::id(),
+            time::get_time().sec,
+            NEXT_MNT_PT.fetch_add(1, Ordering::SeqCst)
+        )
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_create_file() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let name = OsStr::new("a");
+        let now = time::get_time();
+
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, name, 0o644, &OWNER, &now)
+            .unwrap();
+        assert_eq!(ent.size, 0);
+        assert_eq!(ent.segment_len, 4);
+
+        let found = db_mgr.lookup(&mnt_pt, 1, name).unwrap();
+        assert_eq!(found.ino, ent.ino);
+        assert_eq!(found.nlink, 1);
+
+        match db_mgr.create_file(&mnt_pt, 1, name, 0o644, &OWNER, &now) {
+            Err(PgDbFsError::Exists) => {}
+            res => panic!("expected Exists, got {:?}", res),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_writep_segments_read_back() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, OsStr::new("a"), 0o644, &OWNER, &now)
+            .unwrap();
+
+        let segments: Vec<(i64, &[u8])> = vec![(0, b"abcd"), (1, b"ef")];
+        db_mgr.writep_segments(&ent.id, &segments, &now).unwrap();
+        assert_eq!(db_mgr.get_file_sz(&ent.id).unwrap(), 6);
+
+        // Rewriting a segment does not grow the file
+        db_mgr
+            .writep_segments(&ent.id, &[(0, &b"wxyz"[..])], &now)
+            .unwrap();
+        assert_eq!(db_mgr.get_file_sz(&ent.id).unwrap(), 6);
+
+        let seg = db_mgr.load_segment(&ent.id, &0).unwrap();
+        assert_eq!(seg, Some(b"wxyz".to_vec()));
+        let seg = db_mgr.load_segment(&ent.id, &1).unwrap();
+        assert_eq!(seg, Some(b"ef".to_vec()));
+        assert_eq!(db_mgr.load_segment(&ent.id, &2).unwrap(), None);
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_recompute_sizes_keeps_or_cuts_extended_files() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, OsStr::new("a"), 0o644, &OWNER, &now)
+            .unwrap();
+        db_mgr
+            .writep_segments(&ent.id, &[(0, &b"abcd"[..])], &now)
+            .unwrap();
+        db_mgr.truncate(&ent.id, 100, &now).unwrap();
+
+        assert_eq!(db_mgr.recompute_sizes(&mnt_pt, false).unwrap(), 0);
+        assert_eq!(db_mgr.get_file_sz(&ent.id).unwrap(), 100);
+
+        assert_eq!(db_mgr.recompute_sizes(&mnt_pt, true).unwrap(), 1);
+        assert_eq!(db_mgr.get_file_sz(&ent.id).unwrap(), 4);
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_create_symlink() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let target = OsStr::new("../target");
+        let ent = db_mgr
+            .create_symlink(&mnt_pt, 1, OsStr::new("l"), target, &OWNER, &now)
+            .unwrap();
+        assert_eq!(ent.size, 9);
+
+        let found = db_mgr.lookup_by_ino(&mnt_pt, ent.ino).unwrap();
+        assert_eq!(found.link_target, Some(target.to_os_string()));
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_link() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, OsStr::new("a"), 0o644, &OWNER, &now)
+            .unwrap();
+        db_mgr
+            .link(&mnt_pt, ent.ino, 1, OsStr::new("b"), &now)
+            .unwrap();
+
+        let found = db_mgr.lookup(&mnt_pt, 1, OsStr::new("b")).unwrap();
+        assert_eq!(found.ino, ent.ino);
+        assert_eq!(found.nlink, 2);
+
+        match db_mgr.link(&mnt_pt, ent.ino, 1, OsStr::new("a"), &now) {
+            Err(PgDbFsError::Exists) => {}
+            res => panic!("expected Exists, got {:?}", res),
+        }
     }
 
-    fn test_fcache() {
-        let mut db_mgr = PgDbMgr::new(String::from("localhost"));
-        db_mgr.init();
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_non_utf8_name_round_trip() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let name = OsStr::from_bytes(b"caf\xe9-\xff");
+        assert!(name.to_str().is_none());
+
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, name, 0o644, &OWNER, &now)
+            .unwrap();
+        assert_eq!(ent.name, name);
+
+        let found = db_mgr.lookup(&mnt_pt, 1, name).unwrap();
+        assert_eq!(found.ino, ent.ino);
+        assert_eq!(found.name.as_bytes(), b"caf\xe9-\xff");
+
+        let listed = db_mgr.ls(&mnt_pt, 1, 0, 10).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, name);
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_ls_pages_by_dentry_id() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        for name in &["a", "b", "c", "d", "e"] {
+            db_mgr
+                .create_file(&mnt_pt, 1, OsStr::new(name), 0o644, &OWNER, &now)
+                .unwrap();
+        }
+        let names =
+            |page: &[Ent]| -> Vec<OsString> { page.iter().map(|e| e.name.clone()).collect() };
+
+        let page = db_mgr.ls(&mnt_pt, 1, 0, 2).unwrap();
+        assert_eq!(names(&page), vec!["a", "b"]);
+
+        // Entries removed or added between pages neither shift nor repeat
+        // the rest of the listing
+        db_mgr.unlink(&page[0].dentry_id, &now).unwrap();
+        db_mgr
+            .create_file(&mnt_pt, 1, OsStr::new("f"), 0o644, &OWNER, &now)
+            .unwrap();
+
+        let page = db_mgr.ls(&mnt_pt, 1, page[1].dentry_id, 2).unwrap();
+        assert_eq!(names(&page), vec!["c", "d"]);
+        let page = db_mgr.ls(&mnt_pt, 1, page[1].dentry_id, 2).unwrap();
+        assert_eq!(names(&page), vec!["e", "f"]);
+        let page = db_mgr.ls(&mnt_pt, 1, page[1].dentry_id, 2).unwrap();
+        assert!(page.is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_xattr_create_and_replace() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, OsStr::new("a"), 0o644, &OWNER, &now)
+            .unwrap();
+        let name = OsStr::new("user.team");
+
+        // Replace does not create a missing attribute
+        let set = db_mgr.set_xattr(&mnt_pt, ent.ino, name, b"x", XATTR_REPLACE);
+        assert_eq!(set.unwrap(), 0);
+        assert_eq!(db_mgr.get_xattr(&mnt_pt, ent.ino, name).unwrap(), None);
+
+        let set = db_mgr.set_xattr(&mnt_pt, ent.ino, name, b"infra", XATTR_CREATE);
+        assert_eq!(set.unwrap(), 1);
+        // Create leaves an existing attribute alone
+        let set = db_mgr.set_xattr(&mnt_pt, ent.ino, name, b"web", XATTR_CREATE);
+        assert_eq!(set.unwrap(), 0);
+        let value = db_mgr.get_xattr(&mnt_pt, ent.ino, name).unwrap();
+        assert_eq!(value, Some(b"infra".to_vec()));
+
+        let set = db_mgr.set_xattr(&mnt_pt, ent.ino, name, b"web", XATTR_REPLACE);
+        assert_eq!(set.unwrap(), 1);
+        let value = db_mgr.get_xattr(&mnt_pt, ent.ino, name).unwrap();
+        assert_eq!(value, Some(b"web".to_vec()));
 
-        let mut fc = fcache::FCache::new();
+        // Without flags the attribute is created or replaced
+        let other = OsStr::new("user.type");
+        assert_eq!(
+            db_mgr.set_xattr(&mnt_pt, ent.ino, other, b"t", 0).unwrap(),
+            1
+        );
+        assert_eq!(
+            db_mgr.set_xattr(&mnt_pt, ent.ino, name, b"db", 0).unwrap(),
+            1
+        );
+        let listed = db_mgr.list_xattr(&mnt_pt, ent.ino).unwrap();
+        assert_eq!(listed, vec![name, other]);
 
-        fc.init(1);
+        assert_eq!(db_mgr.remove_xattr(&mnt_pt, ent.ino, name).unwrap(), 1);
+        assert_eq!(db_mgr.remove_xattr(&mnt_pt, ent.ino, name).unwrap(), 0);
+    }
 
-        println!("FileCache: {}", fc);
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_mkdir() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let dir = db_mgr
+            .mkdir(&mnt_pt, 1, OsStr::new("d"), 0o755, &OWNER, &now)
+            .unwrap();
+        assert!(dir.is_dir());
+        db_mgr
+            .mkdir(&mnt_pt, dir.ino, OsStr::new("sub"), 0o755, &OWNER, &now)
+            .unwrap();
 
-        let mut fb = fcache::FBuffer::new(1, 1000);
+        let found = db_mgr.lookup(&mnt_pt, 1, OsStr::new("d")).unwrap();
+        assert_eq!(found.ino, dir.ino);
+        assert_eq!(found.nlink, 3);
+        assert!(db_mgr.has_children(&dir.ino).unwrap());
 
-        let mut c: u8 = 0;
-        for i in 0..4 {
-            let mut v: Vec<u8> = Vec::new();
-            for j in 0..8 {
-                c += 1;
-                v.push(c);
-            }
-            fb.add(&v, &db_mgr);
-            println!("{} -- {:?}", i, v);
+        match db_mgr.mkdir(&mnt_pt, 1, OsStr::new("d"), 0o755, &OWNER, &now) {
+            Err(PgDbFsError::Exists) => {}
+            res => panic!("expected Exists, got {:?}", res),
         }
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_rename_type_rules() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let mut fs = test_pgdbfs(&mnt_pt);
+        let now = time::get_time();
+        let top = db_mgr
+            .mkdir(&mnt_pt, 1, OsStr::new("top"), 0o755, &OWNER, &now)
+            .unwrap();
+        let top_ino = top.ino as u64;
+        let dir = db_mgr
+            .mkdir(&mnt_pt, top.ino, OsStr::new("d"), 0o755, &OWNER, &now)
+            .unwrap();
+        let sub = db_mgr
+            .mkdir(&mnt_pt, dir.ino, OsStr::new("sub"), 0o755, &OWNER, &now)
+            .unwrap();
+        let file = db_mgr
+            .create_file(&mnt_pt, top.ino, OsStr::new("f"), 0o644, &OWNER, &now)
+            .unwrap();
+        let full = db_mgr
+            .mkdir(&mnt_pt, top.ino, OsStr::new("full"), 0o755, &OWNER, &now)
+            .unwrap();
+        db_mgr
+            .create_file(&mnt_pt, full.ino, OsStr::new("x"), 0o644, &OWNER, &now)
+            .unwrap();
+        let empty = db_mgr
+            .mkdir(&mnt_pt, top.ino, OsStr::new("empty"), 0o755, &OWNER, &now)
+            .unwrap();
 
-        println!("FBuffer: {:?}", fb);
+        let rename = |fs: &mut fsys::PgDbFs, name: &str, newparent: i64, newname: &str| {
+            fs.rename_atomically(
+                top_ino,
+                OsStr::new(name),
+                newparent as u64,
+                OsStr::new(newname),
+                0,
+            )
+        };
+        assert_eq!(rename(&mut fs, "f", top.ino, "d"), Err(EISDIR));
+        assert_eq!(rename(&mut fs, "d", top.ino, "f"), Err(ENOTDIR));
+        assert_eq!(rename(&mut fs, "d", top.ino, "full"), Err(ENOTEMPTY));
+        assert_eq!(rename(&mut fs, "d", dir.ino, "d2"), Err(EINVAL));
+        assert_eq!(rename(&mut fs, "d", sub.ino, "d2"), Err(EINVAL));
+        assert_eq!(rename(&mut fs, "f", file.ino, "g"), Err(ENOTDIR));
+        assert_eq!(rename(&mut fs, "missing", top.ino, "g"), Err(ENOENT));
 
-        println!("FBuffer: {}", fb);
-        let off: i64 = 1;
-        let len: i32 = 10;
-        let segments = fb.get_segment_indexes(off, len);
+        // Failed renames leave both names as they were
+        let found = db_mgr.lookup(&mnt_pt, top.ino, OsStr::new("f")).unwrap();
+        assert_eq!(found.ino, file.ino);
+        let found = db_mgr.lookup(&mnt_pt, top.ino, OsStr::new("d")).unwrap();
+        assert_eq!(found.ino, dir.ino);
 
-        let read = fb.read(off, len);
-        println!(
-            "$$$$ off: {}, len: {}, indexes: {:?}",
-            off,
-            len,
-            segments.unwrap()
+        assert_eq!(rename(&mut fs, "d", top.ino, "empty"), Ok(()));
+        let found = db_mgr
+            .lookup(&mnt_pt, top.ino, OsStr::new("empty"))
+            .unwrap();
+        assert_eq!(found.ino, dir.ino);
+        match db_mgr.lookup_by_ino(&mnt_pt, empty.ino) {
+            Err(PgDbFsError::NotFound) => {}
+            res => panic!("expected NotFound, got {:?}", res),
+        }
+        assert_eq!(rename(&mut fs, "f", sub.ino, "g"), Ok(()));
+        let found = db_mgr.lookup(&mnt_pt, sub.ino, OsStr::new("g")).unwrap();
+        assert_eq!(found.ino, file.ino);
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_rename_flags() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let mut fs = test_pgdbfs(&mnt_pt);
+        let now = time::get_time();
+        let top = db_mgr
+            .mkdir(&mnt_pt, 1, OsStr::new("top"), 0o755, &OWNER, &now)
+            .unwrap();
+        let top_ino = top.ino as u64;
+        let dir = db_mgr
+            .mkdir(&mnt_pt, top.ino, OsStr::new("d"), 0o755, &OWNER, &now)
+            .unwrap();
+        let file = db_mgr
+            .create_file(&mnt_pt, top.ino, OsStr::new("f"), 0o644, &OWNER, &now)
+            .unwrap();
+        db_mgr
+            .link(&mnt_pt, file.ino, top.ino, OsStr::new("h"), &now)
+            .unwrap();
+
+        let rename = |fs: &mut fsys::PgDbFs, name: &str, newparent: i64, newname: &str, flags| {
+            fs.rename_atomically(
+                top_ino,
+                OsStr::new(name),
+                newparent as u64,
+                OsStr::new(newname),
+                flags,
+            )
+        };
+        let both = RENAME_NOREPLACE | RENAME_EXCHANGE;
+        assert_eq!(rename(&mut fs, "f", top.ino, "g", both), Err(EINVAL));
+        assert_eq!(rename(&mut fs, "f", top.ino, "g", 0x100), Err(EINVAL));
+        assert_eq!(
+            rename(&mut fs, "f", top.ino, "d", RENAME_NOREPLACE),
+            Err(EEXIST)
         );
-        println!("Read data: {:?}", read);
+        assert_eq!(
+            rename(&mut fs, "f", top.ino, "g", RENAME_EXCHANGE),
+            Err(ENOENT)
+        );
+        // A directory can not be exchanged with an entry below it
+        assert_eq!(
+            rename(&mut fs, "d", dir.ino, "d", RENAME_EXCHANGE),
+            Err(EINVAL)
+        );
+
+        // Exchanging swaps the inodes regardless of their types
+        assert_eq!(rename(&mut fs, "f", top.ino, "d", RENAME_EXCHANGE), Ok(()));
+        let found = db_mgr.lookup(&mnt_pt, top.ino, OsStr::new("f")).unwrap();
+        assert_eq!(found.ino, dir.ino);
+        let found = db_mgr.lookup(&mnt_pt, top.ino, OsStr::new("d")).unwrap();
+        assert_eq!(found.ino, file.ino);
+
+        // Two links to one inode swap to the same thing
+        assert_eq!(rename(&mut fs, "d", top.ino, "h", RENAME_EXCHANGE), Ok(()));
+        let a = db_mgr.lookup(&mnt_pt, top.ino, OsStr::new("d")).unwrap();
+        let b = db_mgr.lookup(&mnt_pt, top.ino, OsStr::new("h")).unwrap();
+        db_mgr
+            .exchange_dentries(&a.dentry_id, &b.dentry_id, &now)
+            .unwrap();
+
+        assert_eq!(rename(&mut fs, "d", top.ino, "g", RENAME_NOREPLACE), Ok(()));
+        let found = db_mgr.lookup(&mnt_pt, top.ino, OsStr::new("g")).unwrap();
+        assert_eq!(found.ino, file.ino);
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_fbuffer_save_read_back() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, OsStr::new("a"), 0o644, &OWNER, &now)
+            .unwrap();
+
+        // Enough segments to go through trim_segments as well as save
+        let data: Vec<u8> = (0..100u8).collect();
+        let mut cache = SegmentCache::new(1 << 20);
+        let mut fb = FBuffer::new(ent.id, ent.segment_len, ent.size, now);
+        fb.add(2, &data, now, &mut cache, &mut db_mgr).unwrap();
+        fb.save(&mut db_mgr).unwrap();
+        assert_eq!(db_mgr.get_file_sz(&ent.id).unwrap(), 102);
+
+        let mut cache = SegmentCache::new(1 << 20);
+        let mut fb = FBuffer::new(ent.id, ent.segment_len, 102, now);
+        let read = fb.read(0, 200, 4, &mut cache, &mut db_mgr).unwrap();
+        assert_eq!(&read[..2], &[0, 0]);
+        assert_eq!(&read[2..], &data[..]);
+
+        // Read-ahead stops at the end of the file however large it is set
+        let mut cache = SegmentCache::new(1 << 20);
+        let mut fb = FBuffer::new(ent.id, ent.segment_len, 102, now);
+        let read = fb.read(0, 8, u64::MAX, &mut cache, &mut db_mgr).unwrap();
+        assert_eq!(&read[2..], &data[..6]);
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_truncate_cuts_and_zero_extends() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, OsStr::new("a"), 0o644, &OWNER, &now)
+            .unwrap();
+        db_mgr
+            .writep_segments(
+                &ent.id,
+                &[(0, &b"abcd"[..]), (1, &b"efgh"[..]), (2, &b"ij"[..])],
+                &now,
+            )
+            .unwrap();
+
+        // Cutting drops the trailing segment and shortens the boundary one
+        db_mgr.truncate(&ent.id, 6, &now).unwrap();
+        assert_eq!(db_mgr.get_file_sz(&ent.id).unwrap(), 6);
+        let seg = db_mgr.load_segment(&ent.id, &1).unwrap();
+        assert_eq!(seg, Some(b"ef".to_vec()));
+        assert_eq!(db_mgr.load_segment(&ent.id, &2).unwrap(), None);
+
+        // The cut bytes do not come back when the file is extended again
+        db_mgr.truncate(&ent.id, 12, &now).unwrap();
+        let mut cache = SegmentCache::new(1 << 20);
+        let mut fb = FBuffer::new(ent.id, ent.segment_len, 12, now);
+        let read = fb.read(0, 20, 4, &mut cache, &mut db_mgr).unwrap();
+        assert_eq!(read, b"abcdef\0\0\0\0\0\0".to_vec());
+
+        // A cached buffer with unsaved writes is cut and extended the same way
+        fb.add(0, b"ABCDEFGHIJ", now, &mut cache, &mut db_mgr)
+            .unwrap();
+        db_mgr.truncate(&ent.id, 3, &now).unwrap();
+        fb.truncate(3, now, &mut cache);
+        db_mgr.truncate(&ent.id, 8, &now).unwrap();
+        fb.truncate(8, now, &mut cache);
+        let read = fb.read(0, 20, 4, &mut cache, &mut db_mgr).unwrap();
+        assert_eq!(read, b"ABC\0\0\0\0\0".to_vec());
+
+        fb.save(&mut db_mgr).unwrap();
+        let mut cache = SegmentCache::new(1 << 20);
+        let mut fb = FBuffer::new(ent.id, ent.segment_len, 8, now);
+        let read = fb.read(0, 20, 4, &mut cache, &mut db_mgr).unwrap();
+        assert_eq!(read, b"ABC\0\0\0\0\0".to_vec());
+    }
+
+    #[test]
+    fn test_timespec_to_ns_range() {
+        let ts = Timespec::new(-1, 999_999_999);
+        assert_eq!(timespec_to_ns(&ts).unwrap(), -1);
+        assert_eq!(ns_to_timespec(-1), ts);
+
+        // The last representable time, in the year 2262
+        let last = ns_to_timespec(i64::MAX);
+        assert_eq!(timespec_to_ns(&last).unwrap(), i64::MAX);
+        let past_last = Timespec::new(last.sec, last.nsec + 1);
+        match timespec_to_ns(&past_last) {
+            Err(PgDbFsError::Overflow) => {}
+            res => panic!("expected Overflow, got {:?}", res),
+        }
+        match timespec_to_ns(&Timespec::new(i64::MIN / 1_000_000_000 - 1, 0)) {
+            Err(PgDbFsError::Overflow) => {}
+            res => panic!("expected Overflow, got {:?}", res),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs PGDBFS_TEST_DB_HOST"]
+    fn test_write_keeps_ctime_current() {
+        let mut db_mgr = test_db();
+        let mnt_pt = test_mnt_pt();
+        let now = time::get_time();
+        let ent = db_mgr
+            .create_file(&mnt_pt, 1, OsStr::new("a"), 0o644, &OWNER, &now)
+            .unwrap();
+
+        // A buffer whose mtime was set back by utimens
+        let old = time::Timespec::new(1000, 0);
+        db_mgr
+            .writep_segments(&ent.id, &[(0, &b"abcd"[..])], &old)
+            .unwrap();
+        let ent = db_mgr.lookup_by_ino(&mnt_pt, ent.ino).unwrap();
+        assert_eq!(ent.mtime, old);
+        assert!(ent.ctime >= now);
+    }
+
+    #[test]
+    fn test_fcache_keeps_released_buffer_until_removed() {
+        let mnt_pt = String::from("/tmp/pgdbfs-test");
+        let ent = test_ent(2, "a");
+        let mut fcache = FCache::new();
+        let fh1 = fcache.open(&mnt_pt, &ent, 0);
+        let fh2 = fcache.open(&mnt_pt, &ent, 0);
+
+        assert_eq!(fcache.release(&mnt_pt, fh1), None);
+        assert!(fcache.remove(&mnt_pt, &2).is_none());
+        assert_eq!(fcache.release(&mnt_pt, fh2), Some(2));
+        // A failed save leaves the buffer cached for the next open
+        assert!(fcache.get(&mnt_pt, &2).is_some());
+        let fh3 = fcache.open(&mnt_pt, &ent, 0);
+        assert_eq!(fcache.get(&mnt_pt, &2).unwrap().refs, 1);
+
+        assert_eq!(fcache.release(&mnt_pt, fh3), Some(2));
+        assert!(fcache.remove(&mnt_pt, &2).is_some());
+        assert!(fcache.get(&mnt_pt, &2).is_none());
+    }
+
+    #[test]
+    fn test_icache_evicts_oldest_when_full() {
+        let hour = Duration::hours(1);
+        let mut icache = ICache::new(hour, hour, hour);
+        for ino in 2..70000 {
+            icache.insert(1, &test_ent(ino, &ino.to_string()));
+        }
+
+        match icache.lookup(1, OsStr::new("2")) {
+            CachedLookup::Miss => {}
+            res => panic!("expected Miss, got {:?}", res),
+        }
+        assert!(icache.get_attr(2).is_none());
+        match icache.lookup(1, OsStr::new("69999")) {
+            CachedLookup::Found(ent) => assert_eq!(ent.ino, 69999),
+            res => panic!("expected Found, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_icache_lookup_and_invalidate() {
+        let hour = Duration::hours(1);
+        let mut icache = ICache::new(hour, hour, hour);
+        icache.insert(1, &test_ent(2, "a"));
+
+        match icache.lookup(1, OsStr::new("a")) {
+            CachedLookup::Found(ent) => assert_eq!(ent.ino, 2),
+            res => panic!("expected Found, got {:?}", res),
+        }
+        assert_eq!(icache.get_attr(2).unwrap().ino, 2);
+
+        // The entry is still cached but leads to attributes no longer there
+        icache.invalidate(2);
+        assert!(icache.get_attr(2).is_none());
+        match icache.lookup(1, OsStr::new("a")) {
+            CachedLookup::Miss => {}
+            res => panic!("expected Miss, got {:?}", res),
+        }
+
+        icache.insert(1, &test_ent(2, "a"));
+        icache.invalidate_entry(1, OsStr::new("a"));
+        match icache.lookup(1, OsStr::new("a")) {
+            CachedLookup::Miss => {}
+            res => panic!("expected Miss, got {:?}", res),
+        }
+        assert!(icache.get_attr(2).is_some());
+    }
+
+    #[test]
+    fn test_icache_negative_entries() {
+        let hour = Duration::hours(1);
+        let mut icache = ICache::new(hour, hour, hour);
+        icache.insert_negative(1, OsStr::new("a"));
+        match icache.lookup(1, OsStr::new("a")) {
+            CachedLookup::Negative => {}
+            res => panic!("expected Negative, got {:?}", res),
+        }
+
+        // Creating the name replaces the negative entry
+        icache.insert(1, &test_ent(2, "a"));
+        match icache.lookup(1, OsStr::new("a")) {
+            CachedLookup::Found(ent) => assert_eq!(ent.ino, 2),
+            res => panic!("expected Found, got {:?}", res),
+        }
+
+        // A zero negative ttl disables negative entries
+        let mut icache = ICache::new(hour, hour, Duration::zero());
+        icache.insert_negative(1, OsStr::new("a"));
+        match icache.lookup(1, OsStr::new("a")) {
+            CachedLookup::Miss => {}
+            res => panic!("expected Miss, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_icache_expiry() {
+        let hour = Duration::hours(1);
+        let short = Duration::milliseconds(50);
+
+        let mut icache = ICache::new(short, hour, short);
+        icache.insert(1, &test_ent(2, "a"));
+        icache.insert_negative(1, OsStr::new("b"));
+        thread::sleep(StdDuration::from_millis(100));
+        match icache.lookup(1, OsStr::new("a")) {
+            CachedLookup::Miss => {}
+            res => panic!("expected Miss, got {:?}", res),
+        }
+        match icache.lookup(1, OsStr::new("b")) {
+            CachedLookup::Miss => {}
+            res => panic!("expected Miss, got {:?}", res),
+        }
+        // Attributes have their own ttl
+        assert!(icache.get_attr(2).is_some());
+
+        let mut icache = ICache::new(hour, short, hour);
+        icache.insert(1, &test_ent(2, "a"));
+        thread::sleep(StdDuration::from_millis(100));
+        assert!(icache.get_attr(2).is_none());
+        match icache.lookup(1, OsStr::new("a")) {
+            CachedLookup::Miss => {}
+            res => panic!("expected Miss, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_segment_cache_evicts_least_recently_used() {
+        let mut cache = SegmentCache::new(12);
+        cache.insert(1, 0, vec![0; 4]);
+        cache.insert(1, 1, vec![1; 4]);
+        cache.insert(2, 0, vec![2; 4]);
+
+        // Using segment 0 leaves segment 1 least recently used
+        assert_eq!(cache.get(1, 0), Some(&[0u8; 4][..]));
+        cache.insert(2, 1, vec![3; 4]);
+        assert!(!cache.contains(1, 1));
+        assert!(cache.contains(1, 0));
+        assert!(cache.contains(2, 0));
+        assert!(cache.contains(2, 1));
+
+        // Making room for a larger segment evicts as many as needed
+        cache.insert(3, 0, vec![4; 8]);
+        assert!(!cache.contains(2, 0));
+        assert!(!cache.contains(1, 0));
+        assert!(cache.contains(2, 1));
+        assert!(cache.contains(3, 0));
+
+        // Data larger than the whole budget is not cached
+        cache.insert(4, 0, vec![5; 13]);
+        assert!(!cache.contains(4, 0));
+        assert!(cache.contains(3, 0));
+    }
+
+    #[test]
+    fn test_segment_cache_replace_take_and_invalidate() {
+        let mut cache = SegmentCache::new(8);
+        cache.insert(1, 0, vec![0; 4]);
+        // Replacing a segment frees the space of the old data
+        cache.insert(1, 0, vec![1; 4]);
+        cache.insert(1, 1, vec![2; 4]);
+        assert!(cache.contains(1, 0));
+        assert!(cache.contains(1, 1));
+
+        assert_eq!(cache.take(1, 0), Some(vec![1; 4]));
+        assert!(!cache.contains(1, 0));
+        cache.insert(2, 0, vec![3; 4]);
+        assert!(cache.contains(1, 1));
+
+        cache.invalidate_file(1);
+        assert!(!cache.contains(1, 1));
+        assert!(cache.contains(2, 0));
+    }
+
+    #[test]
+    fn test_segment_cache_counts_hits_and_misses() {
+        let mut cache = SegmentCache::new(8);
+        cache.insert(1, 0, vec![0; 4]);
+
+        assert!(cache.get(1, 0).is_some());
+        assert!(cache.get(1, 1).is_none());
+        assert!(cache.take(1, 0).is_some());
+        assert!(cache.take(1, 0).is_none());
+        assert_eq!(cache.hits, 2);
+        assert_eq!(cache.misses, 2);
     }
 }
@@ -5,6 +5,7 @@ extern crate clap;
 pub mod db;
 pub mod fcache;
 pub mod fsys;
+pub mod icache;
 
 use clap::{App, Arg};
 use std::path::Path;
@@ -36,6 +37,17 @@ fn main() {
                 .takes_value(true)
                 .help("Config file path"),
         )
+        .arg(
+            Arg::with_name("recompute-sizes")
+                .long("recompute-sizes")
+                .help("Recompute file sizes from stored data and exit"),
+        )
+        .arg(
+            Arg::with_name("shrink")
+                .long("shrink")
+                .requires("recompute-sizes")
+                .help("Also cut sizes that end past the stored data"),
+        )
         .get_matches();
 
     let cfg_path_path = Path::new(matches.value_of("config-file").unwrap_or(&cfg_path));
@@ -44,6 +56,15 @@ fn main() {
 
     let mnt_pt = matches.value_of("mount-pt").unwrap();
 
+    if matches.is_present("recompute-sizes") {
+        fsys::recompute_sizes(
+            mnt_pt.to_string(),
+            normalized_config_file_path,
+            matches.is_present("shrink"),
+        );
+        return;
+    }
+
     if Path::new(mnt_pt).exists() {
         let umount_cmd = format!("fusermount -u {}", mnt_pt);
 